@@ -1,12 +1,38 @@
 //! HTTP client that automatically checks requests against robots.txt.
+use crate::ipc;
 use slog::{error, info, Logger};
-use std::time::Duration;
+use std::cell::Cell;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime};
 use ureq::Agent;
 use url::{Host, Url};
 
 /// The string to be matched against "User-agent" in robots.txt
 const USER_AGENT_TOKEN: &str = "MinoruFediverseCrawler";
 
+/// How long a copy of robots.txt can be used before it has to be fetched again. RFC 9309 says
+/// crawlers "SHOULD NOT use the cached version for more than 24 hours".
+const ROBOTS_TXT_CACHE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How much of robots.txt we read. RFC 9309 requires parsing at least 500 KiB.
+const ROBOTS_TXT_MAX_LEN: u64 = 500 * 1024;
+
+/// How many redirects we follow while fetching robots.txt. RFC 9309 says crawlers "SHOULD follow
+/// at least five consecutive redirects, even across authorities".
+const ROBOTS_TXT_REDIRECTS_LIMIT: u8 = 5;
+
+/// The longest `Crawl-delay` we honour. Anything longer is clamped to this, so that a check still
+/// fits into `CHECK_TIME_BUDGET`.
+const MAX_CRAWL_DELAY: Duration = Duration::from_secs(30);
+
+/// How long a single check can take, including the time spent waiting because of `Crawl-delay`.
+const CHECK_TIME_BUDGET: Duration = Duration::from_secs(3 * 60);
+
+/// Longest `ETag` or `Last-Modified` value that we remember. Real ones are way shorter; this just
+/// stops a server from making us store junk.
+const VALIDATOR_MAX_LEN: usize = 256;
+
 /// The string to be sent with each HTTP request.
 const USER_AGENT_FULL: &str = "Minoru's Fediverse Crawler (+https://nodes.fediverse.party)";
 
@@ -25,6 +51,10 @@ pub enum HttpClientError {
     /// The URL couldn't be accessed because the access is forbidden by robots.txt.
     ForbiddenByRobotsTxt(Url),
 
+    /// robots.txt at the given URL couldn't be fetched because of a server error or a timeout, and
+    /// we don't have a cached copy. RFC 9309 says this means that everything is disallowed.
+    RobotsTxtUnavailable(Url),
+
     /// The URL is temporarily redirected to another.
     // The fields are put into a box to avoid clippy::result_large_err warning.
     Moving(Box<Redirection>),
@@ -45,6 +75,9 @@ pub enum HttpClientError {
 
     /// Error parsing a URL with the `url` crate.
     UrlParseError(url::ParseError),
+
+    /// Waiting for `Crawl-delay` before requesting the URL would exceed `CHECK_TIME_BUDGET`.
+    CheckTimeBudgetExceeded(Url),
 }
 
 impl std::fmt::Display for HttpClientError {
@@ -53,6 +86,9 @@ impl std::fmt::Display for HttpClientError {
             HttpClientError::ForbiddenByRobotsTxt(url) => {
                 write!(f, "robots.txt forbids access to {}", url)
             }
+            HttpClientError::RobotsTxtUnavailable(url) => {
+                write!(f, "{} is unavailable, so everything is disallowed", url)
+            }
             HttpClientError::Moving(redir) => {
                 write!(
                     f,
@@ -77,6 +113,13 @@ impl std::fmt::Display for HttpClientError {
             HttpClientError::UrlParseError(err) => {
                 write!(f, "error parsing URL: {}", err)
             }
+            HttpClientError::CheckTimeBudgetExceeded(url) => {
+                write!(
+                    f,
+                    "can't request {} without exceeding the time budget of {:?}",
+                    url, CHECK_TIME_BUDGET
+                )
+            }
         }
     }
 }
@@ -85,51 +128,330 @@ impl std::error::Error for HttpClientError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             HttpClientError::ForbiddenByRobotsTxt(_) => None,
+            HttpClientError::RobotsTxtUnavailable(_) => None,
             HttpClientError::Moving { .. } => None,
             HttpClientError::Moved { .. } => None,
             HttpClientError::NoLocationHeader(_) => None,
             HttpClientError::UreqError(err) => err.source(),
             HttpClientError::UreqStdError(err) => err.source(),
             HttpClientError::UrlParseError(err) => err.source(),
+            HttpClientError::CheckTimeBudgetExceeded(_) => None,
         }
     }
 }
 
 pub struct HttpClient {
     logger: Logger,
-    inner: Agent,
-    robots_txt: String,
+    inner: Agents,
+    robots_txt: ipc::RobotsTxt,
+    robots_txt_is_fresh: bool,
+    pacer: Pacer,
 }
 
-impl HttpClient {
-    pub fn new(logger: Logger, host: Host) -> Result<Self, HttpClientError> {
-        let inner = ureq::AgentBuilder::new()
-            // We'll handle redirects ourselves
-            .redirects(0)
-            .timeout(Duration::from_secs(30))
-            .user_agent(USER_AGENT_FULL)
+/// HTTP agents for direct connections and for each of the proxies.
+struct Agents {
+    direct: Agent,
+    proxied: Vec<(ipc::ProxyRoute, Agent)>,
+}
+
+impl Agents {
+    /// Unless `allow_private_addresses` is `true`, direct connections are only made to global
+    /// addresses. Proxies are trusted to make their own decisions.
+    fn new(
+        proxies: &[ipc::ProxyRoute],
+        allow_private_addresses: bool,
+    ) -> Result<Self, HttpClientError> {
+        let builder = || {
+            ureq::AgentBuilder::new()
+                // We'll handle redirects ourselves
+                .redirects(0)
+                .timeout(Duration::from_secs(30))
+                .user_agent(USER_AGENT_FULL)
+        };
+        let direct = builder()
+            .resolver(VettedResolver {
+                allow_private_addresses,
+            })
             .build();
-        let robots_txt = {
-            let url = format!("https://{}/robots.txt", host);
-            let url = Url::parse(&url).map_err(HttpClientError::UrlParseError)?;
-            info!(logger, "Fetching robots.txt");
-            get_with_type_ignoring_404(&logger, &inner, &url, None)?
-                .into_string()
-                .map_err(HttpClientError::UreqStdError)?
+        let proxied = proxies
+            .iter()
+            .map(|route| {
+                let proxy = ureq::Proxy::new(&route.proxy)
+                    .map_err(|e| HttpClientError::UreqError(Box::new(e)))?;
+                Ok((route.clone(), builder().proxy(proxy).build()))
+            })
+            .collect::<Result<_, HttpClientError>>()?;
+        Ok(Self { direct, proxied })
+    }
+
+    /// The agent through which `url` should be requested.
+    fn for_url(&self, url: &Url) -> &Agent {
+        let host = url.host_str().unwrap_or("");
+        self.proxied
+            .iter()
+            .find(|(route, _)| route.matches(host))
+            .map_or(&self.direct, |(_, agent)| agent)
+    }
+}
+
+/// The host resolved only to addresses that we aren't allowed to connect to.
+#[derive(Debug)]
+pub struct ForbiddenAddressError {
+    netloc: String,
+    addresses: Vec<SocketAddr>,
+}
+
+impl std::fmt::Display for ForbiddenAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} resolves to non-global addresses only: {:?}",
+            self.netloc, self.addresses
+        )
+    }
+}
+
+impl std::error::Error for ForbiddenAddressError {}
+
+/// A resolver that only lets us connect to global addresses, unless `allow_private_addresses` is
+/// set.
+///
+/// Otherwise, any peers list could make us connect to the loopback interface, the local network,
+/// or a cloud metadata service. ureq connects to the addresses returned from here, so the host
+/// can't resolve to a different address between the check and the connection.
+struct VettedResolver {
+    allow_private_addresses: bool,
+}
+
+impl ureq::Resolver for VettedResolver {
+    fn resolve(&self, netloc: &str) -> std::io::Result<Vec<SocketAddr>> {
+        let addresses: Vec<SocketAddr> = netloc.to_socket_addrs()?.collect();
+        if addresses.is_empty() {
+            // ureq reports this as a DNS failure.
+            return Ok(addresses);
+        }
+        if self.allow_private_addresses {
+            return Ok(addresses);
+        }
+        let vetted: Vec<SocketAddr> = addresses
+            .iter()
+            .copied()
+            .filter(|address| is_global(address.ip()))
+            .collect();
+        if vetted.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                ForbiddenAddressError {
+                    netloc: netloc.to_owned(),
+                    addresses,
+                },
+            ));
+        }
+        Ok(vetted)
+    }
+}
+
+/// Returns `true` if the address is globally routable, i.e. not private, loopback, link-local,
+/// reserved for documentation or some special purpose, etc.
+///
+/// This is a conservative version of the unstable `IpAddr::is_global()`.
+fn is_global(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_global_v4(address),
+        IpAddr::V6(address) => is_global_v6(address),
+    }
+}
+
+fn is_global_v4(address: Ipv4Addr) -> bool {
+    let [a, b, c, _] = address.octets();
+    !(address.is_unspecified()
+        || address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_broadcast()
+        || address.is_documentation()
+        || address.is_multicast()
+        // "This network", 0.0.0.0/8
+        || a == 0
+        // Shared address space (carrier-grade NAT), 100.64.0.0/10
+        || (a == 100 && (64..=127).contains(&b))
+        // IETF protocol assignments, 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // 6to4 relay anycast, 192.88.99.0/24
+        || (a == 192 && b == 88 && c == 99)
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && (18..=19).contains(&b))
+        // Reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_global_v6(address: Ipv6Addr) -> bool {
+    // Addresses that embed an IPv4 address are as global as that address.
+    if let Some(v4) = address.to_ipv4_mapped() {
+        return is_global_v4(v4);
+    }
+    let [s0, s1, s2, s3, s4, s5, s6, s7] = address.segments();
+    if (s0, s1, s2, s3, s4, s5) == (0x64, 0xff9b, 0, 0, 0, 0) {
+        // NAT64, 64:ff9b::/96
+        let [a, b] = s6.to_be_bytes();
+        let [c, d] = s7.to_be_bytes();
+        return is_global_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    if s0 == 0x2002 {
+        // 6to4, 2002::/16
+        let [a, b] = s1.to_be_bytes();
+        let [c, d] = s2.to_be_bytes();
+        return is_global_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_multicast()
+        // IPv4-compatible (deprecated), ::/96
+        || (s0, s1, s2, s3, s4, s5) == (0, 0, 0, 0, 0, 0)
+        // Discard-only, 100::/64
+        || (s0, s1, s2, s3) == (0x100, 0, 0, 0)
+        // IETF protocol assignments, 2001::/23
+        || (s0 == 0x2001 && s1 < 0x200)
+        // Documentation, 2001:db8::/32
+        || (s0, s1) == (0x2001, 0xdb8)
+        // Unique local, fc00::/7
+        || (s0 & 0xfe00) == 0xfc00
+        // Link-local and the deprecated site-local, fe80::/10 and fec0::/10
+        || (s0 & 0xffc0) == 0xfe80
+        || (s0 & 0xffc0) == 0xfec0)
+}
+
+/// Spaces requests according to robots.txt's `Crawl-delay`, within `CHECK_TIME_BUDGET`.
+struct Pacer {
+    delay: Duration,
+    started: Instant,
+    last_request: Cell<Option<Instant>>,
+}
+
+impl Pacer {
+    /// Sleep until it's polite to make another request to `url`, then note that the request is
+    /// made.
+    fn wait_before(&self, url: &Url) -> Result<(), HttpClientError> {
+        let now = Instant::now();
+        let next_request = match self.last_request.get() {
+            // `delay` is at most `MAX_CRAWL_DELAY`, so this can't overflow.
+            Some(last) => last.checked_add(self.delay).map_or(now, |at| at.max(now)),
+            None => now,
+        };
+        if next_request.saturating_duration_since(self.started) > CHECK_TIME_BUDGET {
+            return Err(HttpClientError::CheckTimeBudgetExceeded(url.to_owned()));
+        }
+        std::thread::sleep(next_request.saturating_duration_since(now));
+        self.last_request.set(Some(Instant::now()));
+        Ok(())
+    }
+}
+
+impl HttpClient {
+    /// Create a client for the given host.
+    ///
+    /// If the `request` carries a robots.txt that is younger than `ROBOTS_TXT_CACHE_DURATION`,
+    /// it's used instead of fetching robots.txt anew. An older copy is only used if robots.txt is
+    /// currently unavailable. Without a copy, unavailable robots.txt disallows everything, unless
+    /// the request says to ignore that.
+    ///
+    /// Requests to hosts that match one of the request's proxies go through that proxy.
+    pub fn new(
+        logger: Logger,
+        host: Host,
+        request: ipc::CheckerRequest,
+    ) -> Result<Self, HttpClientError> {
+        let started = Instant::now();
+        let inner = Agents::new(&request.proxies, request.allow_private_addresses)?;
+        let ignore_unreachable = request.ignore_unreachable_robots_txt;
+        let (robots_txt, robots_txt_is_fresh) = match request.robots_txt {
+            Some(cached) if is_still_valid(&cached) => {
+                info!(
+                    logger,
+                    "Using robots.txt fetched at {:?}", cached.fetched_at
+                );
+                (cached, false)
+            }
+            cached => {
+                let url = format!("{}/robots.txt", base_url(&host));
+                let url = Url::parse(&url).map_err(HttpClientError::UrlParseError)?;
+                match (fetch_robots_txt(&logger, &inner, &url)?, cached) {
+                    (Some(fetched), _) => (fetched, true),
+                    (None, Some(stale)) => {
+                        info!(
+                            logger,
+                            "robots.txt is unavailable, using a copy fetched at {:?}",
+                            stale.fetched_at
+                        );
+                        (stale, false)
+                    }
+                    (None, None) if ignore_unreachable => {
+                        info!(
+                            logger,
+                            "robots.txt is still unavailable, so treating it as allowing everything"
+                        );
+                        // Any client error means there are no rules.
+                        let unavailable = ipc::RobotsTxt {
+                            body: String::new(),
+                            status: 404,
+                            fetched_at: SystemTime::now(),
+                        };
+                        (unavailable, false)
+                    }
+                    (None, None) => return Err(HttpClientError::RobotsTxtUnavailable(url)),
+                }
+            }
+        };
+        let delay = crawl_delay(&robots_txt);
+        if !delay.is_zero() {
+            info!(logger, "Waiting {:?} between requests", delay);
+        }
+        let pacer = Pacer {
+            delay,
+            started,
+            last_request: Cell::new(robots_txt_is_fresh.then(Instant::now)),
         };
         Ok(Self {
             logger,
             inner,
             robots_txt,
+            robots_txt_is_fresh,
+            pacer,
         })
     }
 
+    /// robots.txt that this client fetched, if it didn't use the cached copy.
+    pub fn fresh_robots_txt(&self) -> Option<&ipc::RobotsTxt> {
+        if self.robots_txt_is_fresh {
+            Some(&self.robots_txt)
+        } else {
+            None
+        }
+    }
+
     pub fn get(&self, url: &Url) -> Result<ureq::Response, HttpClientError> {
+        self.get_conditional(url, None)
+    }
+
+    /// Like `get()`, but if `validators` are given, the server can respond with 304 Not Modified
+    /// instead of sending the whole resource again.
+    pub fn get_conditional(
+        &self,
+        url: &Url,
+        validators: Option<&ipc::Validators>,
+    ) -> Result<ureq::Response, HttpClientError> {
         if !self.allowed_by_robots_txt(url.as_str()) {
             return Err(HttpClientError::ForbiddenByRobotsTxt(url.to_owned()));
         }
 
-        match get_with_type_ignoring_404(&self.logger, &self.inner, url, Some("application/json")) {
+        match get_with_type_ignoring_404(
+            &self.logger,
+            &self.inner,
+            &self.pacer,
+            url,
+            Some("application/json"),
+            validators,
+        ) {
             Ok(r) if r.status() == 404 => {
                 let ureq_err = ureq::Error::Status(404, r);
                 Err(HttpClientError::UreqError(Box::new(ureq_err)))
@@ -139,17 +461,234 @@ impl HttpClient {
     }
 
     fn allowed_by_robots_txt(&self, url: &str) -> bool {
-        use robotstxt::DefaultMatcher;
-        let mut matcher = DefaultMatcher::default();
-        matcher.one_agent_allowed_by_robots(&self.robots_txt, USER_AGENT_TOKEN, url)
+        robots_txt_allows(&self.robots_txt, url)
+    }
+}
+
+/// Returns `true` if `robots_txt` allows us to access `url`.
+///
+/// Per RFC 9309, only a successfully fetched file is parsed. If the server responded with a client
+/// error, or redirected us too many times, there are no rules and everything is allowed; if it
+/// responded with a server error, everything is disallowed.
+fn robots_txt_allows(robots_txt: &ipc::RobotsTxt, url: &str) -> bool {
+    use robotstxt::DefaultMatcher;
+    match robots_txt.status {
+        200..=299 => {
+            let mut matcher = DefaultMatcher::default();
+            matcher.one_agent_allowed_by_robots(&robots_txt.body, USER_AGENT_TOKEN, url)
+        }
+        500..=599 => false,
+        _ => true,
+    }
+}
+
+/// Returns the `Crawl-delay` that `robots_txt` asks us to observe, clamped to `MAX_CRAWL_DELAY`.
+///
+/// The delay is taken from the group for our user-agent token or, if there's no such group, from
+/// the group for all user agents.
+fn crawl_delay(robots_txt: &ipc::RobotsTxt) -> Duration {
+    if !(200..=299).contains(&robots_txt.status) {
+        return Duration::ZERO;
+    }
+    let mut parser = CrawlDelayParser::default();
+    robotstxt::parse_robotstxt(&robots_txt.body, &mut parser);
+    parser
+        .ours
+        .or(parser.global)
+        .unwrap_or(Duration::ZERO)
+        .min(MAX_CRAWL_DELAY)
+}
+
+/// Collects `Crawl-delay` values from robots.txt.
+#[derive(Default)]
+struct CrawlDelayParser {
+    /// Whether the current group applies to us specifically.
+    in_our_group: bool,
+
+    /// Whether the current group applies to all user agents.
+    in_global_group: bool,
+
+    /// Whether the last line was a `User-agent` line, i.e. the next one can add to the group.
+    reading_user_agents: bool,
+
+    ours: Option<Duration>,
+    global: Option<Duration>,
+}
+
+impl robotstxt::RobotsParseHandler for CrawlDelayParser {
+    fn handle_robots_start(&mut self) {}
+
+    fn handle_robots_end(&mut self) {}
+
+    fn handle_user_agent(&mut self, _line_num: u32, user_agent: &str) {
+        if !self.reading_user_agents {
+            self.in_our_group = false;
+            self.in_global_group = false;
+            self.reading_user_agents = true;
+        }
+        let token = user_agent.split_whitespace().next().unwrap_or("");
+        if token == "*" {
+            self.in_global_group = true;
+        } else if token.eq_ignore_ascii_case(USER_AGENT_TOKEN) {
+            self.in_our_group = true;
+        }
+    }
+
+    fn handle_allow(&mut self, _line_num: u32, _value: &str) {
+        self.reading_user_agents = false;
+    }
+
+    fn handle_disallow(&mut self, _line_num: u32, _value: &str) {
+        self.reading_user_agents = false;
+    }
+
+    fn handle_sitemap(&mut self, _line_num: u32, _value: &str) {}
+
+    fn handle_unknown_action(&mut self, _line_num: u32, action: &str, value: &str) {
+        self.reading_user_agents = false;
+        if !action.eq_ignore_ascii_case("crawl-delay") {
+            return;
+        }
+        let Some(delay) = value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        else {
+            return;
+        };
+        if self.in_our_group {
+            self.ours = Some(delay);
+        }
+        if self.in_global_group {
+            self.global = Some(delay);
+        }
+    }
+}
+
+/// The scheme and host that the instance's URLs start with, e.g. "https://example.com".
+///
+/// Onion services are contacted over plain HTTP. Tor already authenticates and encrypts the
+/// connection, so most of them don't have a certificate that would pass validation.
+pub fn base_url(host: &Host) -> String {
+    let is_onion = matches!(
+        host,
+        Host::Domain(domain) if domain.trim_end_matches('.').to_ascii_lowercase().ends_with(".onion")
+    );
+    if is_onion {
+        format!("http://{}", host)
+    } else {
+        format!("https://{}", host)
+    }
+}
+
+/// Validators that the server sent along with the response.
+pub fn validators_of(response: &ureq::Response) -> ipc::Validators {
+    let header = |name| {
+        response
+            .header(name)
+            .filter(|value| value.len() <= VALIDATOR_MAX_LEN)
+            .map(str::to_owned)
+    };
+    ipc::Validators {
+        etag: header("etag"),
+        last_modified: header("last-modified"),
+    }
+}
+
+/// Returns `true` if the cached robots.txt can still be used.
+fn is_still_valid(robots_txt: &ipc::RobotsTxt) -> bool {
+    match SystemTime::now().duration_since(robots_txt.fetched_at) {
+        Ok(age) => age < ROBOTS_TXT_CACHE_DURATION,
+        // The file was fetched "in the future", so something is wrong with the clock. Better fetch
+        // the file again.
+        Err(_) => false,
+    }
+}
+
+/// Fetch robots.txt from `url`, following redirects even if they lead to other hosts.
+///
+/// Returns `None` if robots.txt is unavailable because of a server error or a timeout. Errors that
+/// prevent us from reaching the instance at all, like DNS or connection failures on the first
+/// request, are returned as is: those aren't about robots.txt, the whole instance is unreachable.
+/// Once robots.txt redirects elsewhere, such failures are about that other host, so robots.txt is
+/// merely unavailable.
+fn fetch_robots_txt(
+    logger: &Logger,
+    agents: &Agents,
+    url: &Url,
+) -> Result<Option<ipc::RobotsTxt>, HttpClientError> {
+    info!(logger, "Fetching robots.txt");
+    let fetched_at = SystemTime::now();
+    let mut current_url = url.to_owned();
+    let mut redirects_left = ROBOTS_TXT_REDIRECTS_LIMIT;
+    let response = loop {
+        let request = agents
+            .for_url(&current_url)
+            .get(current_url.as_str())
+            .timeout(Duration::from_secs(10));
+        let response = match request.call() {
+            Ok(r) => r,
+            Err(ureq::Error::Status(_, r)) => r,
+            Err(ureq::Error::Transport(t))
+                if redirects_left == ROBOTS_TXT_REDIRECTS_LIMIT
+                    && matches!(
+                        t.kind(),
+                        ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed
+                    ) =>
+            {
+                return Err(HttpClientError::UreqError(Box::new(t.into())));
+            }
+            Err(e) => {
+                info!(logger, "Failed to fetch robots.txt: {}", e);
+                return Ok(None);
+            }
+        };
+        if !is_redirect(response.status()) || redirects_left == 0 {
+            break response;
+        }
+        match response
+            .header("location")
+            .and_then(|h| current_url.join(h).ok())
+        {
+            Some(to) => current_url = to,
+            // Nowhere to go, so this robots.txt is as unavailable as one that's redirected too
+            // many times.
+            None => break response,
+        }
+        redirects_left = redirects_left.saturating_sub(1);
+    };
+
+    let status = response.status();
+    if (500..=599).contains(&status) {
+        info!(logger, "robots.txt responded with HTTP {}", status);
+        return Ok(None);
     }
+
+    let mut body = vec![];
+    if (200..=299).contains(&status) {
+        response
+            .into_reader()
+            .take(ROBOTS_TXT_MAX_LEN)
+            .read_to_end(&mut body)
+            .map_err(HttpClientError::UreqStdError)?;
+    }
+    let body = String::from_utf8_lossy(&body).into_owned();
+
+    Ok(Some(ipc::RobotsTxt {
+        body,
+        status,
+        fetched_at,
+    }))
 }
 
 fn get_with_type_ignoring_404(
     logger: &Logger,
-    agent: &Agent,
+    agents: &Agents,
+    pacer: &Pacer,
     url: &Url,
     acceptable_type: Option<&str>,
+    validators: Option<&ipc::Validators>,
 ) -> Result<ureq::Response, HttpClientError> {
     // Our redirect policy is:
     // - follow redirects as long as they point to the same hostname:port, and schema didn't
@@ -160,12 +699,20 @@ fn get_with_type_ignoring_404(
     let mut current_url = url.to_owned();
     let mut response;
     loop {
-        let mut request = agent
+        pacer.wait_before(&current_url)?;
+        let mut request = agents
+            .for_url(&current_url)
             .get(current_url.as_str())
             .timeout(Duration::from_secs(10));
         if let Some(t) = acceptable_type {
             request = request.set("Accept", t);
         }
+        if let Some(etag) = validators.and_then(|v| v.etag.as_deref()) {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = validators.and_then(|v| v.last_modified.as_deref()) {
+            request = request.set("If-Modified-Since", last_modified);
+        }
 
         match request.call() {
             Ok(r) => response = r,
@@ -258,6 +805,45 @@ fn is_same_origin(lhs: &Url, rhs: &Url) -> bool {
 mod test {
     use super::*;
 
+    #[test]
+    fn resolver_filters_non_global_addresses() {
+        use ureq::Resolver;
+
+        let loopback: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let global: SocketAddr = "93.184.215.14:443".parse().unwrap();
+
+        let strict = VettedResolver {
+            allow_private_addresses: false,
+        };
+        let error = strict.resolve("127.0.0.1:443").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(error
+            .get_ref()
+            .is_some_and(|error| error.is::<ForbiddenAddressError>()));
+        assert_eq!(strict.resolve("93.184.215.14:443").unwrap(), vec![global]);
+
+        let lenient = VettedResolver {
+            allow_private_addresses: true,
+        };
+        assert_eq!(lenient.resolve("127.0.0.1:443").unwrap(), vec![loopback]);
+        assert_eq!(lenient.resolve("93.184.215.14:443").unwrap(), vec![global]);
+    }
+
+    #[test]
+    fn onion_services_are_contacted_over_http() {
+        let host = |name: &str| Host::Domain(name.to_string());
+        assert_eq!(base_url(&host("example.com")), "https://example.com");
+        assert_eq!(
+            base_url(&host("onion.example.com")),
+            "https://onion.example.com"
+        );
+        assert_eq!(base_url(&host("example.onion")), "http://example.onion");
+        assert_eq!(
+            base_url(&host("foo.example.onion")),
+            "http://foo.example.onion"
+        );
+    }
+
     #[test]
     fn test_origin() {
         let http_example_com = Url::parse("http://example.com").unwrap();
@@ -277,4 +863,205 @@ mod test {
         assert!(is_same_origin(&https_example_com, &https_example_com));
         assert!(is_same_origin(&https_example_com, &https_example_com_443));
     }
+
+    #[test]
+    fn cached_robots_txt_expires_after_a_day() {
+        let robots_txt_fetched = |ago: Duration| ipc::RobotsTxt {
+            body: String::new(),
+            status: 200,
+            fetched_at: SystemTime::now() - ago,
+        };
+        assert!(is_still_valid(&robots_txt_fetched(Duration::from_secs(60))));
+        assert!(is_still_valid(&robots_txt_fetched(Duration::from_secs(
+            23 * 60 * 60
+        ))));
+        assert!(!is_still_valid(&robots_txt_fetched(Duration::from_secs(
+            25 * 60 * 60
+        ))));
+
+        let from_the_future = ipc::RobotsTxt {
+            body: String::new(),
+            status: 200,
+            fetched_at: SystemTime::now() + Duration::from_secs(60 * 60),
+        };
+        assert!(!is_still_valid(&from_the_future));
+    }
+
+    #[test]
+    fn robots_txt_status_decides_whether_body_is_used() {
+        let robots_txt = |status: u16| ipc::RobotsTxt {
+            body: "User-agent: *\nDisallow: /\n".to_string(),
+            status,
+            fetched_at: SystemTime::now(),
+        };
+        let url = "https://example.com/nodeinfo/2.0";
+
+        assert!(!robots_txt_allows(&robots_txt(200), url));
+        for status in [301, 401, 403, 404, 410] {
+            assert!(robots_txt_allows(&robots_txt(status), url), "{}", status);
+        }
+        for status in [500, 503] {
+            assert!(!robots_txt_allows(&robots_txt(status), url), "{}", status);
+        }
+
+        let empty = ipc::RobotsTxt {
+            body: String::new(),
+            status: 503,
+            fetched_at: SystemTime::now(),
+        };
+        assert!(!robots_txt_allows(&empty, url));
+    }
+
+    #[test]
+    fn crawl_delay_prefers_our_group() {
+        let robots_txt = |body: &str| ipc::RobotsTxt {
+            body: body.to_string(),
+            status: 200,
+            fetched_at: SystemTime::now(),
+        };
+
+        assert_eq!(crawl_delay(&robots_txt("")), Duration::ZERO);
+        assert_eq!(
+            crawl_delay(&robots_txt("User-agent: *\nCrawl-delay: 2\n")),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            crawl_delay(&robots_txt(
+                "User-agent: *\nCrawl-delay: 2\n\n\
+                User-agent: SomeBot\nUser-agent: minorufediversecrawler\nCrawl-delay: 0.5\n"
+            )),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            crawl_delay(&robots_txt(
+                "User-agent: MinoruFediverseCrawler\nDisallow: /private\n\n\
+                User-agent: *\nCrawl-delay: 5\n"
+            )),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            crawl_delay(&robots_txt("User-agent: SomeBot\nCrawl-delay: 5\n")),
+            Duration::ZERO
+        );
+        assert_eq!(
+            crawl_delay(&robots_txt("User-agent: *\nCrawl-delay: 3600\n")),
+            MAX_CRAWL_DELAY
+        );
+        assert_eq!(
+            crawl_delay(&robots_txt("User-agent: *\nCrawl-delay: soon\n")),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn robots_txt_is_unavailable_if_its_redirect_target_is_unreachable() {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let agents = Agents::new(&[], true).unwrap();
+
+        // The instance itself can't be reached, which fails the whole check.
+        let unresolvable = Url::parse("http://unresolvable.invalid/robots.txt").unwrap();
+        assert!(fetch_robots_txt(&logger, &agents, &unresolvable).is_err());
+
+        // The instance redirects robots.txt to a host that can't be reached, which is only a
+        // problem for robots.txt.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/robots.txt",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 301 Moved Permanently\r\n\
+                    Location: http://unresolvable.invalid/robots.txt\r\n\
+                    Content-Length: 0\r\n\
+                    Connection: close\r\n\r\n",
+                )
+                .unwrap();
+        });
+        assert!(fetch_robots_txt(&logger, &agents, &url).unwrap().is_none());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn only_global_addresses_are_allowed() {
+        let global = [
+            "1.1.1.1",
+            "8.8.8.8",
+            "100.63.255.255",
+            "100.128.0.0",
+            "172.32.0.1",
+            "2606:4700:4700::1111",
+            "2a00:1450:4001:80b::200e",
+            "::ffff:93.184.216.34",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+        ];
+        for address in global {
+            assert!(is_global(address.parse().unwrap()), "{}", address);
+        }
+
+        let non_global = [
+            "0.0.0.0",
+            "0.1.2.3",
+            "10.1.2.3",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.0.0.8",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::127.0.0.1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+            "2002:a00:1::1",
+            "100::1",
+            "2001:db8::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "fec0::1",
+            "ff02::1",
+        ];
+        for address in non_global {
+            assert!(!is_global(address.parse().unwrap()), "{}", address);
+        }
+    }
+
+    #[test]
+    fn extracts_validators_from_response() {
+        let response: ureq::Response = "HTTP/1.1 200 OK\r\n\
+            ETag: \"abc\"\r\n\
+            Last-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            validators_of(&response),
+            ipc::Validators {
+                etag: Some(r#""abc""#.to_string()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            }
+        );
+
+        let long_etag = "x".repeat(VALIDATOR_MAX_LEN + 1);
+        let response: ureq::Response = format!("HTTP/1.1 200 OK\r\nETag: {}\r\n\r\n", long_etag)
+            .parse()
+            .unwrap();
+        assert!(validators_of(&response).is_empty());
+    }
 }
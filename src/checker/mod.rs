@@ -1,12 +1,16 @@
 mod http_client;
 
 use crate::{
-    checker::http_client::{HttpClient, HttpClientError},
+    checker::http_client::{
+        base_url, validators_of, ForbiddenAddressError, HttpClient, HttpClientError,
+    },
     ipc, with_loc,
 };
 use anyhow::{anyhow, bail, Context};
 use serde::Deserialize;
 use slog::{error, info, o, Logger};
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use url::{Host, Url};
 
 #[derive(Debug)]
@@ -26,6 +30,21 @@ impl std::error::Error for UreqHttpStatusError {
     }
 }
 
+/// A resource fetched with a conditional request.
+enum Conditional<T> {
+    /// The resource changed since the previous check, or we had nothing to validate it against.
+    Modified {
+        body: T,
+        validators: ipc::Validators,
+    },
+
+    /// The resource is the same as during the previous check.
+    NotModified,
+}
+
+/// HTTP 304 Not Modified.
+const NOT_MODIFIED: u16 = 304;
+
 /// Turns a reference to a response into an error if the server returned an HTTP error.
 ///
 /// This mimics `reqwest::Response::error_for_status_ref()`.
@@ -46,93 +65,362 @@ pub fn main(logger: Logger, host: Host) -> anyhow::Result<()> {
     let logger = logger.new(o!("host" => host.to_string()));
     info!(logger, "Started the checker");
 
-    // Here we handle results of redirects. If we don't call `println!` here, the Orchestrator will
-    // mark the host as dead.
-    if let Err(e) = try_check(&logger, host) {
-        if let Some(error) = e.downcast_ref::<HttpClientError>() {
-            match error {
-                HttpClientError::Moving(redir) => {
-                    if let Some(to) = redir.to.host().map(|h| h.to_owned()) {
-                        info!(logger, "Instance is moving to {}", to);
-                        let moving = serde_json::to_string(&ipc::CheckerResponse::State {
-                            state: ipc::InstanceState::Moving { to },
-                        })
-                        .context(with_loc!("Serializing Moving message"))?;
-                        println!("{}", moving);
-                    }
-                }
+    send(&ipc::CheckerResponse::Hello {
+        version: ipc::PROTOCOL_VERSION,
+    })
+    .context(with_loc!("Sending Hello message"))?;
 
-                HttpClientError::Moved(redir) => {
-                    if let Some(to) = redir.to.host().map(|h| h.to_owned()) {
-                        info!(logger, "Instance has moved to {}", to);
-                        let moved = serde_json::to_string(&ipc::CheckerResponse::State {
-                            state: ipc::InstanceState::Moved { to },
-                        })
-                        .context(with_loc!("Serializing Moved message"))?;
-                        println!("{}", moved);
-                    }
-                }
+    let request = read_request().context(with_loc!("Reading the request from stdin"))?;
 
-                // Propagate all other errors upwards. A lack of response from the checker will
-                // make the orchestrator to mark this host as dead.
-                _ => {
-                    error!(logger, "The instance is dead: {:?}", error);
-                }
+    let (stop_heartbeat, heartbeat_stopped) = mpsc::channel::<()>();
+    let heartbeat = std::thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) =
+            heartbeat_stopped.recv_timeout(ipc::HEARTBEAT_INTERVAL)
+        {
+            if send(&ipc::CheckerResponse::Heartbeat).is_err() {
+                break;
             }
+        }
+    });
+
+    let mut alive_reported = false;
+    let result = try_check(&logger, host, request, &mut alive_reported);
+
+    drop(stop_heartbeat);
+    if heartbeat.join().is_err() {
+        error!(logger, "Heartbeat thread panicked");
+    }
+
+    // Here we handle results of redirects. If we don't send a final message here, the Orchestrator
+    // will consider the checker crashed.
+    if let Err(e) = result {
+        let kind = classify_failure(&e, alive_reported);
+        if let Some(state) = redirect_into_state(&logger, &e, alive_reported) {
+            send(&ipc::CheckerResponse::State { state })
+                .context(with_loc!("Sending State message"))?;
+            send(&ipc::CheckerResponse::Done).context(with_loc!("Sending Done message"))?;
         } else {
-            error!(
-                logger,
-                "Couldn't downcast the error to HttpClientError: {:?}", e
-            );
+            error!(logger, "The check failed ({:?}): {:?}", kind, e);
+            send(&ipc::CheckerResponse::Failed { kind })
+                .context(with_loc!("Sending Failed message"))?;
         }
 
         return Err(e);
     }
 
+    send(&ipc::CheckerResponse::Done).context(with_loc!("Sending Done message"))?;
     info!(logger, "Check finished");
 
     Ok(())
 }
 
-fn try_check(logger: &Logger, host: Host) -> anyhow::Result<()> {
-    let client = HttpClient::new(logger.clone(), host.clone())
+/// Read the orchestrator's request from stdin. Empty stdin is the same as an empty request.
+fn read_request() -> anyhow::Result<ipc::CheckerRequest> {
+    let mut request = vec![];
+    std::io::stdin()
+        .lock()
+        .take(ipc::MAX_REQUEST_LEN)
+        .read_to_end(&mut request)
+        .context(with_loc!("Reading stdin"))?;
+    if request.is_empty() {
+        return Ok(ipc::CheckerRequest::default());
+    }
+    serde_json::from_slice(&request).context(with_loc!("Deserializing the request"))
+}
+
+/// Write a message for the orchestrator into stdout.
+fn send(message: &ipc::CheckerResponse) -> anyhow::Result<()> {
+    let message = serde_json::to_string(message).context(with_loc!("Serializing a message"))?;
+    // Heartbeats are sent from a separate thread, so lock stdout to write the message in one go.
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", message).context(with_loc!("Writing a message into stdout"))?;
+    stdout.flush().context(with_loc!("Flushing stdout"))
+}
+
+/// If the check failed because the instance redirected us elsewhere, turn that into a state.
+///
+/// Redirects only tell us something about the instance if they happened before we learned that
+/// the instance is alive. After that, they're just failures of some secondary endpoint.
+fn redirect_into_state(
+    logger: &Logger,
+    error: &anyhow::Error,
+    alive_reported: bool,
+) -> Option<ipc::InstanceState> {
+    if alive_reported {
+        return None;
+    }
+
+    match error.downcast_ref::<HttpClientError>()? {
+        HttpClientError::Moving(redir) => {
+            let to = redir.to.host()?.to_owned();
+            info!(logger, "Instance is moving to {}", to);
+            Some(ipc::InstanceState::Moving { to })
+        }
+
+        HttpClientError::Moved(redir) => {
+            let to = redir.to.host()?.to_owned();
+            info!(logger, "Instance has moved to {}", to);
+            Some(ipc::InstanceState::Moved { to })
+        }
+
+        _ => None,
+    }
+}
+
+/// Returns `true` if the error is due to `Crawl-delay` using up the check's time budget.
+fn is_time_budget_exceeded(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<HttpClientError>(),
+            Some(HttpClientError::CheckTimeBudgetExceeded(_))
+        )
+    })
+}
+
+/// Figure out why the check failed.
+///
+/// `alive_reported` tells if the failure happened after we determined that the instance is alive,
+/// i.e. while fetching the peers list rather than NodeInfo.
+fn classify_failure(error: &anyhow::Error, alive_reported: bool) -> ipc::FailureKind {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<HttpClientError>() {
+            let kind = match error {
+                HttpClientError::ForbiddenByRobotsTxt(_) => {
+                    Some(ipc::FailureKind::ForbiddenByRobotsTxt)
+                }
+                HttpClientError::RobotsTxtUnavailable(_) => {
+                    Some(ipc::FailureKind::RobotsTxtUnavailable)
+                }
+                // If a redirect made it here, `redirect_into_state()` couldn't turn it into
+                // a state, so it's not the instance moving anywhere.
+                HttpClientError::Moving(_) | HttpClientError::Moved(_) => {
+                    Some(ipc::FailureKind::CrossOriginRedirect)
+                }
+                HttpClientError::UreqError(err) => classify_ureq_error(err),
+                HttpClientError::UreqStdError(err) => classify_io_error(err),
+                HttpClientError::CheckTimeBudgetExceeded(_) => {
+                    Some(ipc::FailureKind::CheckTimeBudgetExceeded)
+                }
+                HttpClientError::NoLocationHeader(_) | HttpClientError::UrlParseError(_) => None,
+            };
+            if let Some(kind) = kind {
+                return kind;
+            }
+            break;
+        }
+
+        if let Some(error) = cause.downcast_ref::<UreqHttpStatusError>() {
+            return ipc::FailureKind::HttpStatus {
+                status: error.status,
+            };
+        }
+    }
+
+    if alive_reported {
+        ipc::FailureKind::Other
+    } else {
+        ipc::FailureKind::InvalidNodeInfo
+    }
+}
+
+fn classify_ureq_error(error: &ureq::Error) -> Option<ipc::FailureKind> {
+    let transport = match error {
+        ureq::Error::Status(status, _) => {
+            return Some(ipc::FailureKind::HttpStatus { status: *status })
+        }
+        ureq::Error::Transport(transport) => transport,
+    };
+
+    let io_error = std::error::Error::source(transport)
+        .and_then(|source| source.downcast_ref::<std::io::Error>());
+
+    match transport.kind() {
+        ureq::ErrorKind::Dns => {
+            let is_forbidden = io_error
+                .and_then(|err| err.get_ref())
+                .is_some_and(|err| err.is::<ForbiddenAddressError>());
+            if is_forbidden {
+                return Some(ipc::FailureKind::ForbiddenAddress);
+            }
+            let is_nxdomain = io_error.map(is_nxdomain).unwrap_or(false);
+            if is_nxdomain {
+                Some(ipc::FailureKind::Nxdomain)
+            } else {
+                Some(ipc::FailureKind::DnsError)
+            }
+        }
+        ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io => match io_error {
+            Some(err) => classify_io_error(err),
+            None => Some(ipc::FailureKind::ConnectionFailed),
+        },
+        ureq::ErrorKind::BadStatus | ureq::ErrorKind::BadHeader => Some(ipc::FailureKind::Other),
+        _ => None,
+    }
+}
+
+fn classify_io_error(error: &std::io::Error) -> Option<ipc::FailureKind> {
+    use std::io::ErrorKind;
+
+    match error.kind() {
+        ErrorKind::ConnectionRefused => Some(ipc::FailureKind::ConnectionRefused),
+        // ureq reports read timeouts as `WouldBlock`.
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => Some(ipc::FailureKind::Timeout),
+        // rustls reports its errors as `InvalidData`.
+        ErrorKind::InvalidData => Some(ipc::FailureKind::Tls),
+        ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof
+        | ErrorKind::HostUnreachable
+        | ErrorKind::NetworkUnreachable
+        | ErrorKind::AddrNotAvailable => Some(ipc::FailureKind::ConnectionFailed),
+        _ => None,
+    }
+}
+
+/// Returns `true` if the DNS lookup error means the name doesn't exist.
+///
+/// The standard library turns `getaddrinfo()` errors into strings, so that's what we look at.
+fn is_nxdomain(error: &std::io::Error) -> bool {
+    const NXDOMAIN_MESSAGES: [&str; 2] = [
+        // EAI_NONAME
+        "Name or service not known",
+        // EAI_NODATA
+        "No address associated with hostname",
+    ];
+    let message = error.to_string();
+    NXDOMAIN_MESSAGES
+        .iter()
+        .any(|nxdomain| message.contains(nxdomain))
+}
+
+fn try_check(
+    logger: &Logger,
+    host: Host,
+    request: ipc::CheckerRequest,
+    alive_reported: &mut bool,
+) -> anyhow::Result<()> {
+    let validators = request.validators.clone();
+    let previous_software = request.software.clone();
+    let client = HttpClient::new(logger.clone(), host.clone(), request)
         .context(with_loc!("Initializing HTTP client"))?;
 
-    let software = get_software(logger, &client, &host)
+    if let Some(robots_txt) = client.fresh_robots_txt() {
+        let message = ipc::CheckerResponse::RobotsTxt {
+            robots_txt: robots_txt.clone(),
+        };
+        // An overly long message would make the orchestrator drop the whole check, so we'd rather
+        // not cache such a file.
+        match serde_json::to_string(&message) {
+            Ok(json) if json.len() < ipc::MAX_MESSAGE_LEN => {
+                send(&message).context(with_loc!("Sending RobotsTxt message"))?
+            }
+            Ok(_) => info!(logger, "robots.txt is too large to be cached"),
+            Err(e) => error!(logger, "Failed to serialize robots.txt: {}", e),
+        }
+    }
+
+    let previous_nodeinfo = previous_software
+        .as_deref()
+        .zip(validators.get(&ipc::Endpoint::NodeInfo));
+    let (software, nodeinfo_validators) = get_software(logger, &client, &host, previous_nodeinfo)
         .context(with_loc!("Determining instance's software"))?;
     info!(logger, "{} runs {}", host, software);
 
     let hide_from_list = {
         match is_instance_private(&client, &host, &software) {
             Ok(result) => result,
+            // Running out of time says nothing about the instance, and we'd rather not list it
+            // until we know whether it's private.
+            Err(e) if is_time_budget_exceeded(&e) => return Err(e),
             Err(e) => {
                 info!(logger, "Couldn't check if instance is private: {}", e);
                 false
             }
         }
     };
-    let alive = serde_json::to_string(&ipc::CheckerResponse::State {
+    info!(logger, "The instance is alive");
+    send(&ipc::CheckerResponse::State {
         state: ipc::InstanceState::Alive { hide_from_list },
     })
-    .context(with_loc!("Serializing Alive message"))?;
-    info!(logger, "The instance is alive");
-    println!("{}", alive);
-
-    let peers = get_peers(logger, &client, &host, &software)
-        .context(with_loc!("Fetching instance's peers list"))?;
-    info!(logger, "{} has {} peers", host, peers.len());
-    for instance in peers {
-        let peer = serde_json::to_string(&ipc::CheckerResponse::Peer { peer: instance })
-            .context(with_loc!("Serializing Peer message"))?;
-        println!("{}", peer);
+    .context(with_loc!("Sending Alive message"))?;
+    *alive_reported = true;
+
+    send(&ipc::CheckerResponse::Software {
+        name: software.clone(),
+    })
+    .context(with_loc!("Sending Software message"))?;
+    if let Some(validators) = nodeinfo_validators {
+        send(&ipc::CheckerResponse::Validators {
+            endpoint: ipc::Endpoint::NodeInfo,
+            validators,
+        })
+        .context(with_loc!("Sending NodeInfo validators"))?;
+    }
+
+    let peers = get_peers(
+        logger,
+        &client,
+        &host,
+        &software,
+        validators.get(&ipc::Endpoint::Peers),
+    )
+    .context(with_loc!("Fetching instance's peers list"))?;
+    match peers {
+        Conditional::NotModified => {
+            info!(logger, "{}'s peers list didn't change", host);
+            send(&ipc::CheckerResponse::PeersUnchanged)
+                .context(with_loc!("Sending PeersUnchanged message"))?;
+        }
+        Conditional::Modified {
+            body: peers,
+            validators,
+        } => {
+            info!(logger, "{} has {} peers", host, peers.len());
+            for instance in peers {
+                send(&ipc::CheckerResponse::Peer { peer: instance })
+                    .context(with_loc!("Sending Peer message"))?;
+            }
+            send(&ipc::CheckerResponse::Validators {
+                endpoint: ipc::Endpoint::Peers,
+                validators,
+            })
+            .context(with_loc!("Sending peers list validators"))?;
+        }
     }
 
     Ok(())
 }
 
-fn get_software(logger: &Logger, client: &HttpClient, host: &Host) -> anyhow::Result<String> {
-    let nodeinfo = fetch_nodeinfo(logger, client, host).context(with_loc!("Fetching NodeInfo"))?;
-    serde_json::from_str(&nodeinfo)
+/// Figures out the software that the instance runs.
+///
+/// `previous` is the software name and NodeInfo validators from the previous check, if any. If
+/// NodeInfo didn't change since then, the instance still runs the same software.
+///
+/// Returns the software name, plus the NodeInfo validators if the document was fetched anew.
+fn get_software(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+    previous: Option<(&str, &ipc::Validators)>,
+) -> anyhow::Result<(String, Option<ipc::Validators>)> {
+    let nodeinfo = fetch_nodeinfo(logger, client, host, previous.map(|(_, v)| v))
+        .context(with_loc!("Fetching NodeInfo"))?;
+    let (nodeinfo, validators) = match (nodeinfo, previous) {
+        (Conditional::Modified { body, validators }, _) => (body, validators),
+        (Conditional::NotModified, Some((software, _))) => {
+            info!(logger, "NodeInfo didn't change since the previous check");
+            return Ok((software.to_owned(), None));
+        }
+        (Conditional::NotModified, None) => bail!("Got 304 Not Modified for NodeInfo"),
+    };
+    let software = parse_software(logger, &nodeinfo)?;
+    Ok((software, Some(validators)))
+}
+
+fn parse_software(logger: &Logger, nodeinfo: &str) -> anyhow::Result<String> {
+    serde_json::from_str(nodeinfo)
         .map_err(|err| err.into())
         .and_then(|obj: serde_json::Value| {
             #[allow(clippy::indexing_slicing)] // Indexing into Value returns Value::Null
@@ -180,13 +468,19 @@ struct NodeInfoPointerLink {
     href: String,
 }
 
-fn fetch_nodeinfo(logger: &Logger, client: &HttpClient, host: &Host) -> anyhow::Result<String> {
+fn fetch_nodeinfo(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+    validators: Option<&ipc::Validators>,
+) -> anyhow::Result<Conditional<String>> {
     let pointer = fetch_nodeinfo_pointer(logger, client, host)
         .context(with_loc!("Fetching NodeInfo well-known document"))?;
     let url = pick_highest_supported_nodeinfo_version(&pointer).context(with_loc!(
         "Picking the highest supported NodeInfo version out of JRD document"
     ))?;
-    fetch_nodeinfo_document(logger, client, &url).context(with_loc!("Fetching NodeInfo document"))
+    fetch_nodeinfo_document(logger, client, &url, validators)
+        .context(with_loc!("Fetching NodeInfo document"))
 }
 
 fn fetch_nodeinfo_pointer(
@@ -194,7 +488,7 @@ fn fetch_nodeinfo_pointer(
     client: &HttpClient,
     host: &Host,
 ) -> anyhow::Result<NodeInfoPointer> {
-    let url = format!("https://{}/.well-known/nodeinfo", host);
+    let url = format!("{}/.well-known/nodeinfo", base_url(host));
     let url = Url::parse(&url).context(with_loc!(
         "Formatting URL of the well-known NodeInfo document"
     ))?;
@@ -246,10 +540,14 @@ fn fetch_nodeinfo_document(
     logger: &Logger,
     client: &HttpClient,
     url: &Url,
-) -> anyhow::Result<String> {
+    validators: Option<&ipc::Validators>,
+) -> anyhow::Result<Conditional<String>> {
     let response = client
-        .get(url)
+        .get_conditional(url, validators)
         .context(with_loc!("Fetching NodeInfo document"))?;
+    if response.status() == NOT_MODIFIED {
+        return Ok(Conditional::NotModified);
+    }
     error_for_status_ref(&response).map_err(|err| {
         error!(
             logger, "Failed to fetch NodeInfo: {}", err;
@@ -257,9 +555,11 @@ fn fetch_nodeinfo_document(
         err
     })?;
 
-    response
+    let validators = validators_of(&response);
+    let body = response
         .into_string()
-        .context(with_loc!("Getting NodeInfo document's body"))
+        .context(with_loc!("Getting NodeInfo document's body"))?;
+    Ok(Conditional::Modified { body, validators })
 }
 
 fn get_peers(
@@ -267,13 +567,17 @@ fn get_peers(
     client: &HttpClient,
     host: &Host,
     software: &str,
-) -> anyhow::Result<Vec<Host>> {
+    validators: Option<&ipc::Validators>,
+) -> anyhow::Result<Conditional<Vec<Host>>> {
     match software {
         "mastodon" | "pleroma" | "misskey" | "bookwyrm" | "smithereen" => {
-            get_peers_mastodonish(logger, client, host)
+            get_peers_mastodonish(logger, client, host, validators)
                 .context(with_loc!("Fetching peers list via Mastodon-ish API"))
         }
-        _ => Ok(vec![]),
+        _ => Ok(Conditional::Modified {
+            body: vec![],
+            validators: ipc::Validators::default(),
+        }),
     }
 }
 
@@ -281,14 +585,18 @@ fn get_peers_mastodonish(
     logger: &Logger,
     client: &HttpClient,
     host: &Host,
-) -> anyhow::Result<Vec<Host>> {
-    let url = format!("https://{}/api/v1/instance/peers", host);
+    validators: Option<&ipc::Validators>,
+) -> anyhow::Result<Conditional<Vec<Host>>> {
+    let url = format!("{}/api/v1/instance/peers", base_url(host));
     let url = Url::parse(&url).context(with_loc!(
         "Formatting URL of the Mastodon-ish 'peers' endpoint"
     ))?;
     let response = client
-        .get(&url)
+        .get_conditional(&url, validators)
         .context(with_loc!("Fetching Mastodon-ish peers list"))?;
+    if response.status() == NOT_MODIFIED {
+        return Ok(Conditional::NotModified);
+    }
     error_for_status_ref(&response).map_err(|err| {
         error!(
             logger, "Failed to fetch Mastodon-ish peers: {}", err;
@@ -296,12 +604,17 @@ fn get_peers_mastodonish(
         err
     })?;
 
-    Ok(response
+    let validators = validators_of(&response);
+    let peers = response
         .into_json::<Vec<String>>()
         .context(with_loc!("Parsing Mastodon-ish peers list as JSON"))?
         .into_iter()
         .map(Host::Domain)
-        .collect())
+        .collect();
+    Ok(Conditional::Modified {
+        body: peers,
+        validators,
+    })
 }
 
 fn is_instance_private(client: &HttpClient, host: &Host, software: &str) -> anyhow::Result<bool> {
@@ -340,7 +653,7 @@ fn is_instance_private(client: &HttpClient, host: &Host, software: &str) -> anyh
 }
 
 fn get_statusnet_config(client: &HttpClient, host: &Host) -> anyhow::Result<String> {
-    let url = format!("https://{}/api/statusnet/config.json", host);
+    let url = format!("{}/api/statusnet/config.json", base_url(host));
     let url = Url::parse(&url).context(with_loc!("Formatting URL StatusNet config"))?;
     let response = client
         .get(&url)
@@ -351,7 +664,7 @@ fn get_statusnet_config(client: &HttpClient, host: &Host) -> anyhow::Result<Stri
 }
 
 fn get_siteinfo(client: &HttpClient, host: &Host) -> anyhow::Result<String> {
-    let url = format!("https://{}/siteinfo.json", host);
+    let url = format!("{}/siteinfo.json", base_url(host));
     let url = Url::parse(&url).context(with_loc!("Formatting URL of siteinfo document"))?;
     let response = client
         .get(&url)
@@ -366,6 +679,19 @@ fn get_siteinfo(client: &HttpClient, host: &Host) -> anyhow::Result<String> {
 mod test {
     use super::*;
 
+    #[test]
+    fn running_out_of_time_is_inconclusive() {
+        let url = Url::parse("https://example.com/api/v1/instance/peers").unwrap();
+        let error = anyhow::Error::new(HttpClientError::CheckTimeBudgetExceeded(url))
+            .context("Fetching instance's peers list");
+        assert!(is_time_budget_exceeded(&error));
+        for alive_reported in [false, true] {
+            let kind = classify_failure(&error, alive_reported);
+            assert_eq!(kind, ipc::FailureKind::CheckTimeBudgetExceeded);
+            assert!(kind.is_inconclusive());
+        }
+    }
+
     #[test]
     fn picks_highest_nodeinfo_version() {
         assert!(
@@ -438,4 +764,19 @@ mod test {
         };
         assert_eq!(expected, parsed);
     }
+
+    #[test]
+    fn refusing_to_connect_to_loopback_is_a_forbidden_address() {
+        // The resolver refuses the address before any connection is attempted.
+        let logger = Logger::root(slog::Discard, o!());
+        let host = Host::parse("127.0.0.1").unwrap();
+
+        let error = HttpClient::new(logger, host, ipc::CheckerRequest::default())
+            .err()
+            .expect("connecting to the loopback interface should fail");
+        assert_eq!(
+            classify_failure(&error.into(), false),
+            ipc::FailureKind::ForbiddenAddress
+        );
+    }
 }
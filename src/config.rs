@@ -0,0 +1,255 @@
+//! Settings that can be changed without rebuilding the crawler.
+//!
+//! The settings are read from _minoru-fediverse-crawler.json_ in the working directory. The file
+//! is optional, and so is every setting in it: the missing ones take their default values.
+use crate::{
+    db::{FailurePolicy, StatePolicy},
+    ipc::ProxyRoute,
+    with_loc,
+};
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+const CONFIG_FILENAME: &str = "minoru-fediverse-crawler.json";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How the failures of different kinds affect the instance's state.
+    pub failure_policy: FailurePolicy,
+
+    /// How long instances stay in transitional states, and how often instances are checked.
+    pub state_policy: StatePolicy,
+
+    /// Proxies through which the checkers contact some of the hosts, e.g. a Tor SOCKS port for
+    /// ".onion". The first matching route wins; other hosts are contacted directly.
+    pub proxies: Vec<ProxyRoute>,
+
+    /// Whether alive onion services appear in the list of instances. They are only reachable
+    /// through Tor, which not every user of the list has.
+    pub list_onion_instances: bool,
+
+    /// Whether checkers may connect to private, loopback, and other non-global addresses. This is
+    /// only useful for test environments; in production, it lets anyone who runs an instance make
+    /// us poke at our own network.
+    pub allow_private_addresses: bool,
+
+    /// Limits on checks of hosts that probably run on the same hardware.
+    pub politeness: PolitenessPolicy,
+
+    /// How many hosts under the same registrable domain can appear in the list before the rest
+    /// have to be approved by a moderator.
+    pub moderation_threshold: usize,
+
+    /// How fast the crawler starts checks, across all hosts.
+    pub rate_limit: RateLimit,
+
+    /// Where to write metrics for node_exporter's textfile collector, e.g.
+    /// "/var/lib/prometheus/node-exporter/minoru-fediverse-crawler.prom". No metrics are written
+    /// if this isn't set.
+    pub metrics_textfile: Option<String>,
+
+    /// For how many seconds the outcome of each check is kept. Uptime over periods longer than
+    /// this only covers the checks that are still kept.
+    pub check_history_retention_seconds: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            failure_policy: FailurePolicy::default(),
+            state_policy: StatePolicy::default(),
+            proxies: vec![],
+            list_onion_instances: false,
+            allow_private_addresses: false,
+            politeness: PolitenessPolicy::default(),
+            moderation_threshold: 20,
+            rate_limit: RateLimit::default(),
+            metrics_textfile: None,
+            check_history_retention_seconds: 90 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Limits on how hard we hit hosts that share a registrable domain (e.g. "example.com" for
+/// "foo.example.com") or an IP address. Checks that would exceed them are postponed.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolitenessPolicy {
+    /// How many checks can run at once against hosts under the same registrable domain.
+    pub max_checks_per_domain: usize,
+
+    /// How many checks can run at once against hosts at the same IP address.
+    pub max_checks_per_address: usize,
+
+    /// How many seconds have to pass between the starts of two checks against the same
+    /// registrable domain or IP address.
+    pub min_seconds_between_checks: u64,
+}
+
+impl Default for PolitenessPolicy {
+    fn default() -> Self {
+        Self {
+            max_checks_per_domain: 2,
+            max_checks_per_address: 4,
+            min_seconds_between_checks: 1,
+        }
+    }
+}
+
+/// A cap on the crawler's outbound load, e.g. for when it shares bandwidth with other services.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    /// How many checks can be started per minute, on average.
+    pub checks_per_minute: u32,
+
+    /// How many checks can be started at once after a quiet period.
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            checks_per_minute: 600,
+            burst: 10,
+        }
+    }
+}
+
+/// Read the configuration file, or return the defaults if there is no such file.
+pub fn load() -> anyhow::Result<Config> {
+    let contents = match std::fs::read_to_string(CONFIG_FILENAME) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e).context(with_loc!("Reading the configuration file")),
+    };
+    let config: Config =
+        serde_json::from_str(&contents).context(with_loc!("Parsing the configuration file"))?;
+    config
+        .validate()
+        .context(with_loc!("Validating the configuration file"))?;
+    Ok(config)
+}
+
+impl Config {
+    /// Check the settings that deserialization alone can't check.
+    fn validate(&self) -> anyhow::Result<()> {
+        for route in &self.proxies {
+            ureq::Proxy::new(&route.proxy)
+                .with_context(|| format!("Invalid proxy for \"{}\"", route.suffix))?;
+        }
+        if self.politeness.max_checks_per_domain == 0 || self.politeness.max_checks_per_address == 0
+        {
+            bail!("Politeness limits must allow at least one check at a time");
+        }
+        if self.rate_limit.checks_per_minute == 0 || self.rate_limit.burst == 0 {
+            bail!("Rate limit must allow at least one check");
+        }
+        self.state_policy
+            .validate()
+            .context(with_loc!("Validating the state policy"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::{db::FailureRule, ipc::FailureKind};
+
+    #[test]
+    fn empty_config_gives_defaults() {
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn parses_failure_policy() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "failure_policy": {
+                    "never_alive": [
+                        { "kind": "Nxdomain", "failures": 3 },
+                        { "kind": { "HttpStatus": { "status": 404 } }, "failures": 5 }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.failure_policy.never_alive,
+            vec![
+                FailureRule {
+                    kind: FailureKind::Nxdomain,
+                    failures: 3
+                },
+                FailureRule {
+                    kind: FailureKind::HttpStatus { status: 404 },
+                    failures: 5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn validates_proxies() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "proxies": [
+                    { "suffix": ".onion", "proxy": "socks5://127.0.0.1:9050" },
+                    { "suffix": ".i2p", "proxy": "http://127.0.0.1:4444" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let config: Config = serde_json::from_str(
+            r#"{ "proxies": [ { "suffix": ".onion", "proxy": "gopher://127.0.0.1:9050" } ] }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_politeness_limits() {
+        let config: Config =
+            serde_json::from_str(r#"{ "politeness": { "max_checks_per_domain": 0 } }"#).unwrap();
+        assert_eq!(config.politeness.max_checks_per_address, 4);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_rate_limit() {
+        let config: Config =
+            serde_json::from_str(r#"{ "rate_limit": { "checks_per_minute": 0 } }"#).unwrap();
+        assert_eq!(config.rate_limit.burst, 10);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_check_period_shorter_than_jitter() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "state_policy": {
+                    "check_periods": {
+                        "dead": { "period_seconds": 3600, "jitter_seconds": 3600 }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.state_policy.check_periods.alive,
+            crate::time::CheckPeriod::DAILY
+        );
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(serde_json::from_str::<Config>(r#"{ "no_such_setting": 1 }"#).is_err());
+    }
+}
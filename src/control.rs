@@ -0,0 +1,136 @@
+//! Talk to a running orchestrator through its control socket.
+//!
+//! The client sends a single `Request` as a line of JSON, and the orchestrator answers with
+//! a single `Response`, also a line of JSON.
+use crate::with_loc;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// The control socket, in the orchestrator's working directory.
+pub const SOCKET_FILENAME: &str = "minoru-fediverse-crawler.sock";
+
+/// Maximum length of a request or a response, in bytes.
+pub const MAX_MESSAGE_LEN: u64 = 64 * 1024;
+
+/// How long either side waits for the other one to speak up.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum Request {
+    /// Stop starting new checks. The running ones are allowed to finish.
+    Pause,
+
+    /// Start checks again after `Pause`.
+    Resume,
+
+    /// Check the host as soon as possible, adding it to the database if it's not there yet.
+    CheckNow { host: String },
+
+    /// Generate the list of instances without waiting for the usual time.
+    RegenerateList,
+
+    /// Describe what the orchestrator is doing.
+    Status,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum Response {
+    /// The request was accepted.
+    Ok,
+
+    /// The request couldn't be carried out.
+    Error {
+        message: String,
+    },
+
+    Status(Status),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Status {
+    /// Whether the checks are paused.
+    pub paused: bool,
+
+    /// How many checks are running right now.
+    pub running_checks: usize,
+
+    /// How late the most recently started check was, compared to its scheduled time.
+    pub queue_lag_seconds: u64,
+
+    /// When the list of instances was last generated, in seconds since the Unix epoch.
+    pub last_list_generation: Option<u64>,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "paused: {}", self.paused)?;
+        writeln!(f, "running checks: {}", self.running_checks)?;
+        writeln!(f, "queue lag: {} seconds", self.queue_lag_seconds)?;
+        match self.last_list_generation {
+            Some(timestamp) => write!(f, "last list generation: {} (Unix time)", timestamp),
+            None => write!(f, "last list generation: never"),
+        }
+    }
+}
+
+/// Read a single line of JSON from `reader`.
+pub fn read_message<T, R>(reader: R) -> anyhow::Result<T>
+where
+    T: for<'de> Deserialize<'de>,
+    R: Read,
+{
+    let mut line = String::new();
+    BufReader::new(reader.take(MAX_MESSAGE_LEN))
+        .read_line(&mut line)
+        .context(with_loc!("Reading a message"))?;
+    serde_json::from_str(&line).context(with_loc!("Deserializing a message"))
+}
+
+/// Write `message` into `writer` as a single line of JSON.
+pub fn write_message<T, W>(mut writer: W, message: &T) -> anyhow::Result<()>
+where
+    T: Serialize,
+    W: Write,
+{
+    let message = serde_json::to_string(message).context(with_loc!("Serializing a message"))?;
+    writeln!(writer, "{}", message).context(with_loc!("Writing a message"))?;
+    writer.flush().context(with_loc!("Flushing the message"))
+}
+
+/// Send the request to the orchestrator and print its response.
+pub fn main(request: Request) -> anyhow::Result<()> {
+    let stream = UnixStream::connect(SOCKET_FILENAME).context(with_loc!(
+        "Connecting to the control socket; is the orchestrator running in this directory?"
+    ))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .context(with_loc!("Setting a read timeout"))?;
+    write_message(&stream, &request).context(with_loc!("Sending the request"))?;
+
+    match read_message(&stream).context(with_loc!("Reading the response"))? {
+        Response::Ok => println!("OK"),
+        Response::Status(status) => println!("{}", status),
+        Response::Error { message } => bail!("The orchestrator refused: {}", message),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_are_single_lines() {
+        let request = Request::CheckNow {
+            host: "example.com".to_string(),
+        };
+        let mut buffer = vec![];
+        write_message(&mut buffer, &request).unwrap();
+        assert_eq!(buffer.iter().filter(|&&byte| byte == b'\n').count(), 1);
+        assert_eq!(read_message::<Request, _>(&buffer[..]).unwrap(), request);
+    }
+}
@@ -0,0 +1,33 @@
+//! Print statistics about the instances in the database.
+use crate::db::{self, InstanceState};
+use crate::time::Env;
+
+pub fn main() -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+
+    for (state, count) in db::count_instances_by_state(&conn)? {
+        println!("{}: {}", state, count);
+
+        if state == InstanceState::Dying {
+            for (reason, count) in db::count_failure_reasons(&conn, state)? {
+                println!("    {}: {}", reason, count);
+            }
+        }
+    }
+
+    let uptime = db::count_uptime(&conn, Env::system())?;
+    println!("checks that found the instance alive:");
+    for (period, percent) in [
+        ("last 7 days", uptime.last_7_days),
+        ("last 30 days", uptime.last_30_days),
+        ("last 90 days", uptime.last_90_days),
+    ] {
+        match percent {
+            Some(percent) => println!("    {}: {}%", period, percent),
+            None => println!("    {}: no checks", period),
+        }
+    }
+
+    Ok(())
+}
@@ -1,16 +1,30 @@
 //! Functions to query and update the database, plus some helpers.
-
-use crate::{domain::Domain, time, with_loc};
-use anyhow::{anyhow, Context};
+//!
+//! Functions that update several rows do so in a savepoint rather than a transaction, so they can
+//! be run either on their own or as a part of a larger transaction.
+
+use crate::{
+    domain::Domain,
+    ipc::{Endpoint, FailureKind, RobotsTxt, Validators},
+    time::{self, CheckPeriod, Env},
+    with_loc,
+};
+use anyhow::{anyhow, bail, Context};
 use rusqlite::{
     params,
     types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
-    Connection, ToSql, Transaction,
+    Connection, ToSql,
 };
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const ONE_WEEK_IN_SECONDS: u64 = 60 * 60 * 24 * 7;
 
+/// A "dead" or "moved" instance that no peers list mentioned for this long has dropped out of the
+/// lists. If it shows up in one again, its backoff starts over.
+const UNLISTED_PERIOD_SECONDS: u64 = ONE_WEEK_IN_SECONDS;
+
 fn is_sqlite_busy_error(error: &anyhow::Error) -> bool {
     if let Some(error) = error.downcast_ref::<rusqlite::Error>() {
         if let Some(code) = error.sqlite_error_code() {
@@ -104,6 +118,20 @@ pub enum InstanceState {
     Moved = 5,
 }
 
+impl std::fmt::Display for InstanceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            InstanceState::Discovered => "discovered",
+            InstanceState::Alive => "alive",
+            InstanceState::Dying => "dying",
+            InstanceState::Dead => "dead",
+            InstanceState::Moving => "moving",
+            InstanceState::Moved => "moved",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl ToSql for InstanceState {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
         Ok(ToSqlOutput::from(*self as i64))
@@ -125,6 +153,325 @@ impl FromSql for InstanceState {
     }
 }
 
+/// What the moderator decided about a large group of instances that share a registrable domain,
+/// mapped to integers used in the database.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ModerationDecision {
+    /// Nobody looked at the group yet. Hosts beyond the threshold are held out of the list.
+    Pending = 0,
+
+    /// All hosts in the group can be listed.
+    Approved = 1,
+
+    /// None of the hosts in the group can be listed.
+    Rejected = 2,
+}
+
+impl std::fmt::Display for ModerationDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ModerationDecision::Pending => "pending",
+            ModerationDecision::Approved => "approved",
+            ModerationDecision::Rejected => "rejected",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ToSql for ModerationDecision {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+
+impl FromSql for ModerationDecision {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        match v {
+            0 => Ok(Self::Pending),
+            1 => Ok(Self::Approved),
+            2 => Ok(Self::Rejected),
+            _ => Err(rusqlite::types::FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+/// How a check went, as far as uptime is concerned, mapped to integers used in the database.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CheckOutcome {
+    /// The instance responded with valid NodeInfo.
+    Alive = 0,
+
+    /// The instance didn't respond properly.
+    Failed = 1,
+
+    /// The instance redirected us to another host.
+    Moved = 2,
+
+    /// The check couldn't tell whether the instance is alive, e.g. because robots.txt couldn't be
+    /// fetched. Such checks don't count towards uptime.
+    Inconclusive = 3,
+}
+
+impl ToSql for CheckOutcome {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+
+/// A finished check, as kept in the check history.
+#[derive(Debug, Clone, Copy)]
+pub struct CheckRecord {
+    pub outcome: CheckOutcome,
+
+    /// How long the checker ran.
+    pub duration: Duration,
+
+    /// The size of the peers list, if it was fetched in full.
+    pub peers_count: Option<u64>,
+}
+
+/// Share of the checks that found the instance alive, in percent, over the last 7, 30, and 90
+/// days. `None` if there were no checks in that period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Uptime {
+    pub last_7_days: Option<f64>,
+    pub last_30_days: Option<f64>,
+    pub last_90_days: Option<f64>,
+}
+
+/// Maps `Endpoint` to integers used in the database.
+fn endpoint_to_sql(endpoint: Endpoint) -> i64 {
+    match endpoint {
+        Endpoint::NodeInfo => 0,
+        Endpoint::Peers => 1,
+    }
+}
+
+/// The reverse of `endpoint_to_sql()`.
+fn endpoint_from_sql(value: i64) -> anyhow::Result<Endpoint> {
+    match value {
+        0 => Ok(Endpoint::NodeInfo),
+        1 => Ok(Endpoint::Peers),
+        _ => Err(anyhow!("Unknown endpoint {}", value)),
+    }
+}
+
+/// Maps `FailureKind` to integers used in the database, plus an HTTP status code where relevant.
+fn failure_kind_to_sql(kind: FailureKind) -> (i64, Option<u16>) {
+    match kind {
+        FailureKind::Nxdomain => (0, None),
+        FailureKind::DnsError => (1, None),
+        FailureKind::ConnectionRefused => (2, None),
+        FailureKind::ConnectionFailed => (3, None),
+        FailureKind::Tls => (4, None),
+        FailureKind::Timeout => (5, None),
+        FailureKind::HttpStatus { status } => (6, Some(status)),
+        FailureKind::ForbiddenByRobotsTxt => (7, None),
+        FailureKind::InvalidNodeInfo => (8, None),
+        FailureKind::CrossOriginRedirect => (9, None),
+        FailureKind::TemporaryRedirect => (10, None),
+        FailureKind::InvalidRedirect => (11, None),
+        FailureKind::NoResponse => (12, None),
+        FailureKind::Other => (13, None),
+        FailureKind::RobotsTxtUnavailable => (14, None),
+        FailureKind::ForbiddenAddress => (15, None),
+        FailureKind::CheckTimeBudgetExceeded => (16, None),
+    }
+}
+
+/// The reverse of `failure_kind_to_sql()`.
+fn failure_kind_from_sql(kind: i64, http_status: Option<u16>) -> FromSqlResult<FailureKind> {
+    match kind {
+        0 => Ok(FailureKind::Nxdomain),
+        1 => Ok(FailureKind::DnsError),
+        2 => Ok(FailureKind::ConnectionRefused),
+        3 => Ok(FailureKind::ConnectionFailed),
+        4 => Ok(FailureKind::Tls),
+        5 => Ok(FailureKind::Timeout),
+        6 => Ok(FailureKind::HttpStatus {
+            status: http_status.unwrap_or(0),
+        }),
+        7 => Ok(FailureKind::ForbiddenByRobotsTxt),
+        8 => Ok(FailureKind::InvalidNodeInfo),
+        9 => Ok(FailureKind::CrossOriginRedirect),
+        10 => Ok(FailureKind::TemporaryRedirect),
+        11 => Ok(FailureKind::InvalidRedirect),
+        12 => Ok(FailureKind::NoResponse),
+        13 => Ok(FailureKind::Other),
+        14 => Ok(FailureKind::RobotsTxtUnavailable),
+        15 => Ok(FailureKind::ForbiddenAddress),
+        16 => Ok(FailureKind::CheckTimeBudgetExceeded),
+        _ => Err(FromSqlError::OutOfRange(kind)),
+    }
+}
+
+/// Decides which failures are bad enough to skip the week-long "dying" state.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailurePolicy {
+    /// Rules for instances that were never seen alive. If any of these match, the instance goes
+    /// straight to the "dead" state.
+    pub never_alive: Vec<FailureRule>,
+
+    /// After this many checks in a row couldn't fetch robots.txt because of a server or network
+    /// error, robots.txt is treated as allowing everything and the instance is checked anyway. RFC
+    /// 9309 allows this once robots.txt has been unreachable "for a reasonably long period of
+    /// time". Without it, an instance that fails every request would never die.
+    pub robots_txt_unavailable_checks: u64,
+}
+
+/// "`failures` consecutive failures of the given `kind`".
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FailureRule {
+    pub kind: FailureKind,
+    pub failures: u64,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        Self {
+            // Peers lists are full of typos and long-expired domains. If a domain doesn't resolve
+            // the first two times we look, there's no point in waiting a week for it.
+            never_alive: vec![FailureRule {
+                kind: FailureKind::Nxdomain,
+                failures: 2,
+            }],
+            // About a week of daily checks.
+            robots_txt_unavailable_checks: 7,
+        }
+    }
+}
+
+impl FailurePolicy {
+    /// Returns `true` if `consecutive_failures` failures of the given kind are enough to give up on
+    /// an instance that was never seen alive.
+    fn is_hopeless(&self, kind: FailureKind, consecutive_failures: u64) -> bool {
+        self.never_alive
+            .iter()
+            .any(|rule| rule.kind == kind && consecutive_failures >= rule.failures)
+    }
+}
+
+/// Decides how long instances stay in the "dying" and "moving" states, and how often instances in
+/// each state are checked.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatePolicy {
+    /// How long an instance has to keep failing before it's considered dead.
+    pub dying_grace_period_seconds: u64,
+
+    /// How many checks in a row have to fail before an instance is considered dead. The grace
+    /// period has to be over, too.
+    pub dying_failed_checks: u64,
+
+    /// How long an instance has to keep redirecting to the same host before it's considered moved.
+    pub moving_grace_period_seconds: u64,
+
+    /// How many checks in a row have to be redirected to the same host before an instance is
+    /// considered moved. The grace period has to be over, too.
+    pub moving_redirects: u64,
+
+    /// How often instances in each state are checked.
+    pub check_periods: CheckPeriods,
+
+    /// The longest period between checks of "dead" and "moved" instances. Their checks are spaced
+    /// further apart the longer they stay in those states, see `reschedule()`.
+    pub max_backoff_seconds: u64,
+}
+
+impl Default for StatePolicy {
+    fn default() -> Self {
+        // "Daily" checks are run every 29 hours; 1 week = 7 days = 168 hours, that's 5.8 "daily"
+        // checks per real week. So 7 failed checks mean "we've been failing for about a week".
+        Self {
+            dying_grace_period_seconds: ONE_WEEK_IN_SECONDS,
+            dying_failed_checks: 7,
+            moving_grace_period_seconds: ONE_WEEK_IN_SECONDS,
+            moving_redirects: 7,
+            check_periods: CheckPeriods::default(),
+            max_backoff_seconds: 90 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl StatePolicy {
+    /// Check the settings that deserialization alone can't check.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for state in [
+            InstanceState::Discovered,
+            InstanceState::Alive,
+            InstanceState::Dying,
+            InstanceState::Dead,
+            InstanceState::Moving,
+            InstanceState::Moved,
+        ] {
+            let period = self.check_periods.of(state);
+            if period.period_seconds <= period.jitter_seconds {
+                bail!(
+                    "The check period for {} instances must be longer than its jitter",
+                    state
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if an instance that entered a transitional state at `since` has stayed there
+    /// long enough, and was checked enough times, to leave it.
+    fn is_settled(
+        grace_period_seconds: u64,
+        min_checks: u64,
+        since: SystemTime,
+        checks: u64,
+        now: SystemTime,
+    ) -> bool {
+        let grace_period_is_over = since
+            .checked_add(Duration::from_secs(grace_period_seconds))
+            .is_some_and(|end| end <= now);
+        grace_period_is_over && checks >= min_checks
+    }
+}
+
+/// How often instances in each state are checked.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CheckPeriods {
+    pub discovered: CheckPeriod,
+    pub alive: CheckPeriod,
+    pub dying: CheckPeriod,
+    pub dead: CheckPeriod,
+    pub moving: CheckPeriod,
+    pub moved: CheckPeriod,
+}
+
+impl Default for CheckPeriods {
+    fn default() -> Self {
+        Self {
+            discovered: CheckPeriod::DAILY,
+            alive: CheckPeriod::DAILY,
+            dying: CheckPeriod::DAILY,
+            dead: CheckPeriod::WEEKLY,
+            moving: CheckPeriod::DAILY,
+            moved: CheckPeriod::WEEKLY,
+        }
+    }
+}
+
+impl CheckPeriods {
+    pub fn of(&self, state: InstanceState) -> CheckPeriod {
+        match state {
+            InstanceState::Discovered => self.discovered,
+            InstanceState::Alive => self.alive,
+            InstanceState::Dying => self.dying,
+            InstanceState::Dead => self.dead,
+            InstanceState::Moving => self.moving,
+            InstanceState::Moved => self.moved,
+        }
+    }
+}
+
 /// Connect to the database.
 pub fn open() -> anyhow::Result<Connection> {
     let conn = Connection::open("minoru-fediverse-crawler.db")
@@ -265,11 +612,268 @@ pub fn init(conn: &mut Connection) -> anyhow::Result<()> {
         "Creating index 'hidden_instances_hide_from_list_instance'"
     ))?;
 
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS failure_kinds(
+            id INTEGER PRIMARY KEY NOT NULL,
+            kind TEXT UNIQUE NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'failure_kinds'"))?;
+    // These kinds are mapped to `FailureKind` by `failure_kind_to_sql()`.
+    tx.execute(
+        r#"INSERT OR IGNORE INTO failure_kinds (id, kind)
+        VALUES
+            (0, "nxdomain"),
+            (1, "dns_error"),
+            (2, "connection_refused"),
+            (3, "connection_failed"),
+            (4, "tls"),
+            (5, "timeout"),
+            (6, "http_status"),
+            (7, "forbidden_by_robots_txt"),
+            (8, "invalid_nodeinfo"),
+            (9, "cross_origin_redirect"),
+            (10, "temporary_redirect"),
+            (11, "invalid_redirect"),
+            (12, "no_response"),
+            (13, "other"),
+            (14, "robots_txt_unavailable"),
+            (15, "forbidden_address"),
+            (16, "check_time_budget_exceeded")"#,
+        [],
+    )
+    .context(with_loc!("Filling table 'failure_kinds'"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS failure_reasons(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            kind REFERENCES failure_kinds(id) NOT NULL,
+            http_status INTEGER,
+            failed_at INTEGER NOT NULL,
+            repeats INTEGER NOT NULL DEFAULT 1
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'failure_reasons'"))?;
+    add_column_if_missing(
+        &tx,
+        "failure_reasons",
+        "repeats",
+        "INTEGER NOT NULL DEFAULT 1",
+    )
+    .context(with_loc!("Adding column 'repeats' to 'failure_reasons'"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS robots_txt(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            body TEXT NOT NULL,
+            status INTEGER NOT NULL,
+            fetched_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'robots_txt'"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS http_validators(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL,
+            endpoint INTEGER NOT NULL,
+            etag TEXT,
+            last_modified TEXT,
+            UNIQUE(instance, endpoint)
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'http_validators'"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS instance_software(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            software TEXT NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'instance_software'"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS in_flight_checks(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            started_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'in_flight_checks'"))?;
+
+    // Instances in the "dead" and "moved" states, since when their checks are backed off, and when
+    // they were last seen in a peers list.
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS backoff_state_data(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            backoff_since INTEGER NOT NULL,
+            last_listed_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'backoff_state_data'"))?;
+    // Databases created before backoff was introduced start backing off now.
+    tx.execute(
+        "INSERT OR IGNORE
+        INTO backoff_state_data(instance, backoff_since, last_listed_at)
+        SELECT id, strftime('%s', CURRENT_TIMESTAMP), strftime('%s', CURRENT_TIMESTAMP)
+        FROM instances
+        WHERE state IN (?1, ?2)",
+        params![InstanceState::Dead, InstanceState::Moved],
+    )
+    .context(with_loc!("Filling table 'backoff_state_data'"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS check_outcomes(
+            id INTEGER PRIMARY KEY NOT NULL,
+            outcome TEXT UNIQUE NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'check_outcomes'"))?;
+    // These outcomes are mapped to `CheckOutcome`.
+    tx.execute(
+        r#"INSERT OR IGNORE INTO check_outcomes (id, outcome)
+        VALUES
+            (0, "alive"),
+            (1, "failed"),
+            (2, "moved"),
+            (3, "inconclusive")"#,
+        [],
+    )
+    .context(with_loc!("Filling table 'check_outcomes'"))?;
+    // Outcome 3 used to only mean that robots.txt was unavailable.
+    tx.execute(
+        r#"UPDATE check_outcomes
+        SET outcome = "inconclusive"
+        WHERE id = 3"#,
+        [],
+    )
+    .context(with_loc!("Renaming outcome 3 in table 'check_outcomes'"))?;
+    // Checks done within the retention period; see `record_check()`.
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS check_history(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL,
+            checked_at INTEGER NOT NULL,
+            outcome REFERENCES check_outcomes(id) NOT NULL,
+            duration_ms INTEGER NOT NULL,
+            peers_count INTEGER
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'check_history'"))?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS check_history_checked_at
+        ON check_history(checked_at)",
+        [],
+    )
+    .context(with_loc!("Creating index 'check_history_checked_at'"))?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS check_history_instance_checked_at
+        ON check_history(instance, checked_at)",
+        [],
+    )
+    .context(with_loc!(
+        "Creating index 'check_history_instance_checked_at'"
+    ))?;
+
+    // Every change of an instance's state, for debugging. Rows are never changed or deleted.
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS state_transitions(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL,
+            at INTEGER NOT NULL,
+            old_state REFERENCES states(id) NOT NULL,
+            new_state REFERENCES states(id) NOT NULL,
+            reason TEXT NOT NULL,
+            redirect_target REFERENCES instances(id)
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'state_transitions'"))?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS state_transitions_instance_at
+        ON state_transitions(instance, at)",
+        [],
+    )
+    .context(with_loc!("Creating index 'state_transitions_instance_at'"))?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS state_transitions_at
+        ON state_transitions(at)",
+        [],
+    )
+    .context(with_loc!("Creating index 'state_transitions_at'"))?;
+    for (trigger, action) in [
+        ("state_transitions_no_update", "UPDATE"),
+        ("state_transitions_no_delete", "DELETE"),
+    ] {
+        tx.execute(
+            &format!(
+                "CREATE TRIGGER IF NOT EXISTS {}
+                BEFORE {} ON state_transitions
+                BEGIN
+                    SELECT RAISE(ABORT, 'state_transitions is append-only');
+                END",
+                trigger, action
+            ),
+            [],
+        )
+        .with_context(|| format!("Creating trigger '{}'", trigger))?;
+    }
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS moderation_groups(
+            id INTEGER PRIMARY KEY NOT NULL,
+            registrable_domain TEXT UNIQUE NOT NULL,
+            decision INTEGER NOT NULL DEFAULT 0,
+            held INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'moderation_groups'"))?;
+
     tx.commit().context(with_loc!("Committing the transaction"))
 }
 
+/// Add a column to a table created by an older version of the crawler.
+fn add_column_if_missing(
+    tx: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let exists: bool = tx
+        .query_row(
+            "SELECT count(*) > 0
+            FROM pragma_table_info(?1)
+            WHERE name = ?2",
+            params![table, column],
+            |row| row.get(0),
+        )
+        .context(with_loc!("Looking up the column"))?;
+    if !exists {
+        tx.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )
+        .context(with_loc!("Adding the column"))?;
+    }
+    Ok(())
+}
+
 /// For any check whose time has already passed, move that check up to 24 hours from now.
-pub fn reschedule_missed_checks(conn: &mut Connection) -> anyhow::Result<()> {
+pub fn reschedule_missed_checks(conn: &mut Connection, env: Env) -> anyhow::Result<()> {
     let tx = conn
         .transaction()
         .context(with_loc!("Beginning a transaction"))?;
@@ -279,14 +883,14 @@ pub fn reschedule_missed_checks(conn: &mut Connection) -> anyhow::Result<()> {
             .prepare(
                 "SELECT id
                 FROM instances
-                WHERE next_check_datetime < strftime('%s', CURRENT_TIMESTAMP)",
+                WHERE next_check_datetime < ?1",
             )
             .context(with_loc!("Preparing a SELECT"))?;
-        let mut ids = statement.query([])?;
+        let mut ids = statement.query(params![UnixTimestamp(env.now())])?;
         while let Some(row) = ids.next()? {
             let instance_id: i64 = row.get(0).context(with_loc!("Getting `instance_id`"))?;
             let next_check =
-                time::sometime_today().context(with_loc!("Picking next check's datetime"))?;
+                time::sometime_today(env).context(with_loc!("Picking next check's datetime"))?;
             reschedule_instance_to(&tx, instance_id, next_check)
                 .context(with_loc!("Rescheduling instance"))?;
         }
@@ -300,16 +904,20 @@ pub fn mark_alive(
     conn: &mut Connection,
     instance: &Domain,
     hide_from_list: bool,
+    policy: &StatePolicy,
+    env: Env,
 ) -> anyhow::Result<()> {
     let tx = conn
-        .transaction()
-        .context(with_loc!("Beginning a transaction"))?;
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
 
     let (instance_id, state) =
         get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
 
     set_hide_instance_from_list(&tx, instance_id, hide_from_list)
         .context(with_loc!("Updating the flag in `hidden_instances`"))?;
+    delete_failure_reason(&tx, instance_id)
+        .context(with_loc!("Deleting from table 'failure_reasons'"))?;
 
     if state == InstanceState::Alive {
         return tx
@@ -330,12 +938,21 @@ pub fn mark_alive(
         _ => {}
     }
 
-    set_instance_state(&tx, instance_id, InstanceState::Alive)
+    let transition = Transition {
+        from: state,
+        to: InstanceState::Alive,
+        reason: "responded with valid NodeInfo",
+        redirect_target: None,
+    };
+    set_instance_state(&tx, instance_id, &transition, env.now())
         .context(with_loc!("Marking instance as alive"))?;
 
     if state == InstanceState::Dead || state == InstanceState::Moved {
-        let next_check =
-            time::about_a_day_from_now().context(with_loc!("Picking next check's datetime"))?;
+        let next_check = policy
+            .check_periods
+            .alive
+            .next_check(env)
+            .context(with_loc!("Picking next check's datetime"))?;
         reschedule_instance_to(&tx, instance_id, next_check)
             .context(with_loc!("Rescheduling instance"))?;
     }
@@ -343,20 +960,34 @@ pub fn mark_alive(
     tx.commit().context(with_loc!("Committing the transaction"))
 }
 
-/// Note down that the instance is dead.
+/// Note down that the instance is dead, and why.
 ///
-/// This will first move the instance into a "dying" state, and after a week of calling this
-/// function, it will finally move the instance into the "dead" state.
-pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()> {
+/// This will first move the instance into a "dying" state, and after the grace period set by
+/// `StatePolicy`, it will finally move the instance into the "dead" state. Instances that were
+/// never seen alive can take a shortcut, see `FailurePolicy`.
+pub fn mark_dead(
+    conn: &mut Connection,
+    instance: &Domain,
+    reason: FailureKind,
+    failure_policy: &FailurePolicy,
+    state_policy: &StatePolicy,
+    env: Env,
+) -> anyhow::Result<()> {
     let tx = conn
-        .transaction()
-        .context(with_loc!("Beginning a transaction"))?;
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
 
-    let now = SystemTime::now();
+    let now = env.now();
     let (instance_id, state) =
         get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+
+    let consecutive_failures = set_failure_reason(&tx, instance_id, reason, now)
+        .context(with_loc!("Recording the failure reason"))?;
+
     if state == InstanceState::Dead {
-        return Ok(());
+        return tx
+            .commit()
+            .context(with_loc!("Committing the transaction early"));
     }
 
     assert_ne!(state, InstanceState::Dead);
@@ -385,7 +1016,13 @@ pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()>
             )
             .context(with_loc!("Inserting into table 'dying_state_data'"))?;
 
-            set_instance_state(&tx, instance_id, InstanceState::Dying)
+            let transition = Transition {
+                from: state,
+                to: InstanceState::Dying,
+                reason: &reason.to_string(),
+                redirect_target: None,
+            };
+            set_instance_state(&tx, instance_id, &transition, env.now())
                 .context(with_loc!("Marking instance as dying"))?;
         }
 
@@ -397,46 +1034,89 @@ pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()>
                 params![instance_id],
             )
             .context(with_loc!("Updating table 'dying_state_data'"))?;
-
-            let (checks_count, since): (u64, SystemTime) = tx
-                .query_row(
-                    "SELECT failed_checks_count, dying_since
-                    FROM dying_state_data
-                    WHERE instance = ?1",
-                    params![instance_id],
-                    |row| {
-                        let failed_checks_count = row.get(0)?;
-                        let dying_since: UnixTimestamp = row.get(1)?;
-                        Ok((failed_checks_count, dying_since.0))
-                    },
-                )
-                .context(with_loc!("Selecting data from 'dying_state_data'"))?;
-            let one_week = Duration::from_secs(ONE_WEEK_IN_SECONDS);
-            let week_ago = now
-                .checked_sub(one_week)
-                .ok_or_else(|| anyhow!("Couldn't subtract a week from today's datetime"))?;
-            // "Daily" checks are run every 29 hours; 1 week = 7 days = 168 hours, that's 5.8
-            // "daily" checks per peal week. So 6 failed checks means "we've been failing for about
-            // a week".
-            if checks_count > 6 && since < week_ago {
-                delete_from_hidden_instances(&tx, instance_id)
-                    .context(with_loc!("Deleting from 'hidden_instances'"))?;
-                delete_dying_state_data(&tx, instance_id)
-                    .context(with_loc!("Deleting from table 'dying_state_data'"))?;
-                let next_check = time::about_a_week_from_now()
-                    .context(with_loc!("Picking next check's datetime"))?;
-                reschedule_instance_to(&tx, instance_id, next_check)
-                    .context(with_loc!("Rescheduling instance"))?;
-                set_instance_state(&tx, instance_id, InstanceState::Dead)
-                    .context(with_loc!("Marking instance as dead"))?;
-            }
         }
     }
 
+    let (previous_state, checks_count, since): (InstanceState, u64, SystemTime) = tx
+        .query_row(
+            "SELECT previous_state, failed_checks_count, dying_since
+            FROM dying_state_data
+            WHERE instance = ?1",
+            params![instance_id],
+            |row| {
+                let previous_state = row.get(0)?;
+                let failed_checks_count = row.get(1)?;
+                let dying_since: UnixTimestamp = row.get(2)?;
+                Ok((previous_state, failed_checks_count, dying_since.0))
+            },
+        )
+        .context(with_loc!("Selecting data from 'dying_state_data'"))?;
+
+    let grace_period_is_over = StatePolicy::is_settled(
+        state_policy.dying_grace_period_seconds,
+        state_policy.dying_failed_checks,
+        since,
+        checks_count,
+        now,
+    );
+    let never_was_alive = previous_state == InstanceState::Discovered;
+    let is_hopeless = never_was_alive && failure_policy.is_hopeless(reason, consecutive_failures);
+
+    if grace_period_is_over || is_hopeless {
+        delete_from_hidden_instances(&tx, instance_id)
+            .context(with_loc!("Deleting from 'hidden_instances'"))?;
+        delete_dying_state_data(&tx, instance_id)
+            .context(with_loc!("Deleting from table 'dying_state_data'"))?;
+        let next_check = state_policy
+            .check_periods
+            .dead
+            .next_check(env)
+            .context(with_loc!("Picking next check's datetime"))?;
+        reschedule_instance_to(&tx, instance_id, next_check)
+            .context(with_loc!("Rescheduling instance"))?;
+        let why = if grace_period_is_over {
+            format!("{}, and failing for {} checks", reason, checks_count)
+        } else {
+            format!(
+                "{} {} times in a row, and never seen alive",
+                reason, consecutive_failures
+            )
+        };
+        let transition = Transition {
+            from: InstanceState::Dying,
+            to: InstanceState::Dead,
+            reason: &why,
+            redirect_target: None,
+        };
+        set_instance_state(&tx, instance_id, &transition, env.now())
+            .context(with_loc!("Marking instance as dead"))?;
+    }
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+/// Note down that the check failed in a way that tells nothing about the instance being alive or
+/// dead, e.g. because its robots.txt is unavailable. The state doesn't change; only the failure
+/// reason is recorded.
+pub fn mark_inconclusive(
+    conn: &mut Connection,
+    instance: &Domain,
+    reason: FailureKind,
+    env: Env,
+) -> anyhow::Result<()> {
+    let tx = conn
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
+
+    let (instance_id, _state) =
+        get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+    set_failure_reason(&tx, instance_id, reason, env.now())
+        .context(with_loc!("Recording the failure reason"))?;
+
     tx.commit().context(with_loc!("Committing the transaction"))
 }
 
-fn is_moving_to_that_host_already(tx: &Transaction, from: i64, to: i64) -> anyhow::Result<bool> {
+fn is_moving_to_that_host_already(tx: &Connection, from: i64, to: i64) -> anyhow::Result<bool> {
     Ok(tx.query_row(
         "SELECT count(id)
         FROM moving_state_data
@@ -450,7 +1130,7 @@ fn is_moving_to_that_host_already(tx: &Transaction, from: i64, to: i64) -> anyho
     )?)
 }
 
-fn has_moved_to_that_host_already(tx: &Transaction, from: i64, to: i64) -> anyhow::Result<bool> {
+fn has_moved_to_that_host_already(tx: &Connection, from: i64, to: i64) -> anyhow::Result<bool> {
     Ok(tx.query_row(
         "SELECT count(id)
         FROM moved_state_data
@@ -466,20 +1146,40 @@ fn has_moved_to_that_host_already(tx: &Transaction, from: i64, to: i64) -> anyho
 
 /// Note down that the instance has moved to another.
 ///
-/// This will initially mark the instance with the "moving" state, and after calling this function
-/// for a week, it will finally mark the instance as "moved". Changing the target instance resets
+/// This will initially mark the instance with the "moving" state, and after the grace period set by
+/// `StatePolicy`, it will finally mark the instance as "moved". Changing the target instance resets
 /// the count.
-pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyhow::Result<()> {
+pub fn mark_moved(
+    conn: &mut Connection,
+    instance: &Domain,
+    to: &Domain,
+    policy: &StatePolicy,
+    env: Env,
+) -> anyhow::Result<()> {
     let tx = conn
-        .transaction()
-        .context(with_loc!("Beginning a transaction"))?;
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
 
-    let now = SystemTime::now();
+    let now = env.now();
     let (instance_id, state) =
         get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+    delete_failure_reason(&tx, instance_id)
+        .context(with_loc!("Deleting from table 'failure_reasons'"))?;
+
+    // The target might be new to us, whatever state this instance is in
+    let next_check =
+        time::sometime_today(env).context(with_loc!("Picking next check's datatime"))?;
+    tx.execute(
+        "INSERT OR IGNORE
+        INTO instances(hostname, next_check_datetime)
+        VALUES (?1, ?2)",
+        params![to.to_string(), UnixTimestamp(next_check)],
+    )
+    .context(with_loc!("Inserting into table 'instances'"))?;
+    let (to_instance_id, _) =
+        get_instance(&tx, to).context(with_loc!("Getting id of the target instance"))?;
+
     if state == InstanceState::Moved {
-        let (to_instance_id, _) =
-            get_instance(&tx, to).context(with_loc!("Getting instance id"))?;
         let already_moved_there = has_moved_to_that_host_already(&tx, instance_id, to_instance_id)
             .context(with_loc!("Checking if moved to that instance already"))?;
         if !already_moved_there {
@@ -495,7 +1195,13 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
             )
             .context(with_loc!("Inserting into 'moving_state_data'"))?;
 
-            set_instance_state(&tx, instance_id, InstanceState::Moving)
+            let transition = Transition {
+                from: state,
+                to: InstanceState::Moving,
+                reason: "redirects to a different host now",
+                redirect_target: Some(to_instance_id),
+            };
+            set_instance_state(&tx, instance_id, &transition, env.now())
                 .context(with_loc!("Marking instance as moving"))?;
         }
 
@@ -516,18 +1222,6 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
         | InstanceState::Alive
         | InstanceState::Dying
         | InstanceState::Dead => {
-            let next_check =
-                time::sometime_today().context(with_loc!("Picking next check's datatime"))?;
-            tx.execute(
-                "INSERT OR IGNORE
-                INTO instances(hostname, next_check_datetime)
-                VALUES (?1, ?2)",
-                params![to.to_string(), UnixTimestamp(next_check)],
-            )
-            .context(with_loc!("Inserting into table 'instances'"))?;
-            let (to_instance_id, _) = get_instance(&tx, to)
-                .context(with_loc!("Getting id of the newly inserted instance"))?;
-
             tx.execute(
                 "INSERT INTO moving_state_data(instance, previous_state, moving_since, moving_to)
                 VALUES (?1, ?2, ?3, ?4)",
@@ -535,13 +1229,17 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
             )
             .context(with_loc!("Inserting into 'moving_state_data'"))?;
 
-            set_instance_state(&tx, instance_id, InstanceState::Moving)
+            let transition = Transition {
+                from: state,
+                to: InstanceState::Moving,
+                reason: "permanent redirect",
+                redirect_target: Some(to_instance_id),
+            };
+            set_instance_state(&tx, instance_id, &transition, env.now())
                 .context(with_loc!("Marking instance as moving"))?;
         }
 
         InstanceState::Moving => {
-            let (to_instance_id, _) =
-                get_instance(&tx, to).context(with_loc!("Getting instance id"))?;
             let already_moving_there =
                 is_moving_to_that_host_already(&tx, instance_id, to_instance_id)
                     .context(with_loc!("Checking if moving to that instance already"))?;
@@ -555,7 +1253,7 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
                 )
                 .context(with_loc!("Updating table 'moving_state_data'"))?;
 
-                // If the instance is in "moving" state for over a week, consider it moved
+                // If the instance is in "moving" state for long enough, consider it moved
                 let (redirects_count, since): (u64, SystemTime) = tx
                     .query_row(
                         "SELECT redirects_count, moving_since
@@ -569,14 +1267,13 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
                         },
                     )
                     .context(with_loc!("Getting data from 'moving_state_data'"))?;
-                let one_week = Duration::from_secs(ONE_WEEK_IN_SECONDS);
-                let week_ago = now
-                    .checked_sub(one_week)
-                    .ok_or_else(|| anyhow!("Couldn't subtract a week from today's datetime"))?;
-                // "Daily" checks are run every 29 hours; 1 week = 7 days = 168 hours, that's 5.8
-                // "daily" checks per peal week. So 6 redirects mean "we've been redirected for
-                // about a week".
-                if redirects_count > 6 && since < week_ago {
+                if StatePolicy::is_settled(
+                    policy.moving_grace_period_seconds,
+                    policy.moving_redirects,
+                    since,
+                    redirects_count,
+                    now,
+                ) {
                     delete_from_hidden_instances(&tx, instance_id)
                         .context(with_loc!("Deleting from 'hidden_instances'"))?;
                     delete_moving_state_data(&tx, instance_id)
@@ -587,11 +1284,21 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
                         params![instance_id, to_instance_id],
                     )
                     .context(with_loc!("Inserting into 'moved_state_data'"))?;
-                    let next_check = time::about_a_week_from_now()
+                    let next_check = policy
+                        .check_periods
+                        .moved
+                        .next_check(env)
                         .context(with_loc!("Picking next check's datetime"))?;
                     reschedule_instance_to(&tx, instance_id, next_check)
                         .context(with_loc!("Rescheduling instance"))?;
-                    set_instance_state(&tx, instance_id, InstanceState::Moved)
+                    let why = format!("redirected for {} checks", redirects_count);
+                    let transition = Transition {
+                        from: InstanceState::Moving,
+                        to: InstanceState::Moved,
+                        reason: &why,
+                        redirect_target: Some(to_instance_id),
+                    };
+                    set_instance_state(&tx, instance_id, &transition, env.now())
                         .context(with_loc!("Marking instance as moved"))?;
                 }
             } else {
@@ -613,7 +1320,7 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
 }
 
 /// Attempt to add an instance to the database. Does nothing if the instance is already known.
-pub fn add_instance(conn: &Connection, instance: &Domain) -> anyhow::Result<()> {
+pub fn add_instance(conn: &Connection, instance: &Domain, env: Env) -> anyhow::Result<()> {
     let mut statement = conn
         .prepare_cached(
             "INSERT OR IGNORE
@@ -621,7 +1328,8 @@ pub fn add_instance(conn: &Connection, instance: &Domain) -> anyhow::Result<()>
             VALUES (?1, ?2)",
         )
         .context(with_loc!("Preparing cached INSERT OR IGNORE statement"))?;
-    let next_check = time::sometime_today().context(with_loc!("Picking next check's datetime"))?;
+    let next_check =
+        time::sometime_today(env).context(with_loc!("Picking next check's datetime"))?;
     statement
         .execute(params![instance.to_string(), UnixTimestamp(next_check)])
         .context(with_loc!("Executing the statement"))?;
@@ -629,37 +1337,136 @@ pub fn add_instance(conn: &Connection, instance: &Domain) -> anyhow::Result<()>
     Ok(())
 }
 
-/// Reschedule the instance according to its state.
-pub fn reschedule(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()> {
+/// Reschedule the instances according to their states.
+///
+/// "Dead" and "moved" instances back off exponentially: the next check waits as long as the
+/// instance has already been in that state, which doubles the total time with every check. The
+/// wait is at least the usual period for the state, and at most `StatePolicy::max_backoff_seconds`.
+fn reschedule(
+    tx: &Connection,
+    instances: &[Domain],
+    policy: &StatePolicy,
+    env: Env,
+) -> anyhow::Result<()> {
+    let now = env.now();
+    for instance in instances {
+        let (instance_id, state) =
+            get_instance(tx, instance).context(with_loc!("Getting instance id and state"))?;
+
+        let mut period = policy.check_periods.of(state);
+        if let Some(since) =
+            get_backoff_since(tx, instance_id).context(with_loc!("Getting backoff start"))?
+        {
+            let elapsed = now.duration_since(since).unwrap_or(Duration::ZERO);
+            period = period.stretched(elapsed, Duration::from_secs(policy.max_backoff_seconds));
+        }
+        let next_check_datetime = period
+            .next_check(env)
+            .context(with_loc!("Picking next check's datetime"))?;
+
+        reschedule_instance_to(tx, instance_id, next_check_datetime)
+            .context(with_loc!("Rescheduling instance"))?;
+    }
+
+    Ok(())
+}
+
+/// Reschedule the instances, and note that their checks are in flight until `finish_check()` is
+/// called for them.
+///
+/// If the crawler stops before the checks are finished, `requeue_in_flight_checks()` will put them
+/// back into the queue.
+pub fn start_checks(
+    conn: &mut Connection,
+    instances: &[Domain],
+    policy: &StatePolicy,
+    env: Env,
+) -> anyhow::Result<()> {
+    let tx = conn
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
+
+    {
+        let mut statement = tx
+            .prepare_cached(
+                "INSERT OR REPLACE
+                INTO in_flight_checks(instance, started_at)
+                SELECT id, ?2
+                FROM instances
+                WHERE hostname = ?1",
+            )
+            .context(with_loc!("Preparing an INSERT"))?;
+        let now = UnixTimestamp(env.now());
+        for instance in instances {
+            statement
+                .execute(params![instance.to_string(), now])
+                .context(with_loc!("Inserting into table 'in_flight_checks'"))?;
+        }
+    }
+
+    reschedule(&tx, instances, policy, env).context(with_loc!("Rescheduling instances"))?;
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+/// Note that the instance's check is no longer in flight, whatever its outcome.
+pub fn finish_check(conn: &Connection, instance: &Domain) -> anyhow::Result<()> {
+    conn.execute(
+        "DELETE FROM in_flight_checks
+        WHERE instance = (SELECT id FROM instances WHERE hostname = ?1)",
+        params![instance.to_string()],
+    )
+    .map(|_| ())
+    .context(with_loc!("Deleting from table 'in_flight_checks'"))
+}
+
+/// Put the checks that were in flight when the crawler stopped back into the queue, to be done
+/// within a couple of minutes.
+///
+/// Returns the number of re-queued checks.
+pub fn requeue_in_flight_checks(conn: &mut Connection, env: Env) -> anyhow::Result<usize> {
     let tx = conn
         .transaction()
         .context(with_loc!("Beginning a transaction"))?;
 
-    let (instance_id, state) =
-        get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
-
-    let next_check_datetime = match state {
-        InstanceState::Discovered => time::about_a_day_from_now(),
-        InstanceState::Alive => time::about_a_day_from_now(),
-        InstanceState::Dying => time::about_a_day_from_now(),
-        InstanceState::Dead => time::about_a_week_from_now(),
-        InstanceState::Moving => time::about_a_day_from_now(),
-        InstanceState::Moved => time::about_a_week_from_now(),
+    let ids = {
+        let mut statement = tx
+            .prepare("SELECT instance FROM in_flight_checks")
+            .context(with_loc!("Preparing a SELECT"))?;
+        let ids = statement
+            .query_map([], |row| row.get(0))
+            .context(with_loc!("Selecting from 'in_flight_checks'"))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+        ids
+    };
+    for &instance_id in &ids {
+        let next_check =
+            time::in_about_a_minute(env).context(with_loc!("Picking next check's datetime"))?;
+        reschedule_instance_to(&tx, instance_id, next_check)
+            .context(with_loc!("Rescheduling instance"))?;
     }
-    .context(with_loc!("Picking next check's datetiem"))?;
+    tx.execute("DELETE FROM in_flight_checks", [])
+        .context(with_loc!("Deleting from table 'in_flight_checks'"))?;
 
-    tx.execute(
-        "UPDATE instances
-        SET next_check_datetime = ?1
-        WHERE id = ?2",
-        params![UnixTimestamp(next_check_datetime), instance_id],
-    )
-    .context(with_loc!("Updating table 'instances'"))?;
+    tx.commit()
+        .context(with_loc!("Committing the transaction"))?;
+    Ok(ids.len())
+}
+
+/// Move the instance's next check to `until`, leaving everything else as is.
+pub fn postpone(conn: &mut Connection, instance: &Domain, until: SystemTime) -> anyhow::Result<()> {
+    let tx = conn
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
+
+    let (instance_id, _state) =
+        get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+    reschedule_instance_to(&tx, instance_id, until).context(with_loc!("Rescheduling instance"))?;
 
     tx.commit().context(with_loc!("Committing the transaction"))
 }
 
-fn get_instance(tx: &Transaction, instance: &Domain) -> anyhow::Result<(i64, InstanceState)> {
+fn get_instance(tx: &Connection, instance: &Domain) -> anyhow::Result<(i64, InstanceState)> {
     tx.query_row(
         "SELECT id, state
         FROM instances
@@ -674,7 +1481,7 @@ fn get_instance(tx: &Transaction, instance: &Domain) -> anyhow::Result<(i64, Ins
     .context(with_loc!("Getting instance's id and state"))
 }
 
-fn delete_dying_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
+fn delete_dying_state_data(tx: &Connection, id: i64) -> anyhow::Result<()> {
     tx.execute(
         "DELETE FROM dying_state_data
         WHERE instance = ?1",
@@ -684,7 +1491,7 @@ fn delete_dying_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
     .context(with_loc!("Deleting from table `dying_state_data'"))
 }
 
-fn delete_moving_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
+fn delete_moving_state_data(tx: &Connection, id: i64) -> anyhow::Result<()> {
     tx.execute(
         "DELETE FROM moving_state_data
         WHERE instance = ?1",
@@ -694,7 +1501,7 @@ fn delete_moving_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
     .context(with_loc!("Deleting from table 'moving_state_data'"))
 }
 
-fn delete_moved_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
+fn delete_moved_state_data(tx: &Connection, id: i64) -> anyhow::Result<()> {
     tx.execute(
         "DELETE FROM moved_state_data
         WHERE instance = ?1",
@@ -704,8 +1511,54 @@ fn delete_moved_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
     .context(with_loc!("Deleting from table 'moved_state_data'"))
 }
 
+/// Record the reason of the latest failure. Returns the number of consecutive failures with that
+/// same reason.
+fn set_failure_reason(
+    tx: &Connection,
+    id: i64,
+    reason: FailureKind,
+    failed_at: SystemTime,
+) -> anyhow::Result<u64> {
+    let (kind, http_status) = failure_kind_to_sql(reason);
+    tx.execute(
+        "INSERT
+        INTO failure_reasons(instance, kind, http_status, failed_at)
+        VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(instance) DO UPDATE
+        SET repeats = CASE
+                WHEN kind = excluded.kind AND http_status IS excluded.http_status
+                THEN repeats + 1
+                ELSE 1
+            END,
+            kind = excluded.kind,
+            http_status = excluded.http_status,
+            failed_at = excluded.failed_at",
+        params![id, kind, http_status, UnixTimestamp(failed_at)],
+    )
+    .context(with_loc!("Upserting into table 'failure_reasons'"))?;
+
+    tx.query_row(
+        "SELECT repeats
+        FROM failure_reasons
+        WHERE instance = ?1",
+        params![id],
+        |row| row.get(0),
+    )
+    .context(with_loc!("Selecting from table 'failure_reasons'"))
+}
+
+fn delete_failure_reason(tx: &Connection, id: i64) -> anyhow::Result<()> {
+    tx.execute(
+        "DELETE FROM failure_reasons
+        WHERE instance = ?1",
+        params![id],
+    )
+    .map(|_| ())
+    .context(with_loc!("Deleting from table 'failure_reasons'"))
+}
+
 fn reschedule_instance_to(
-    tx: &Transaction,
+    tx: &Connection,
     id: i64,
     next_check_datetime: SystemTime,
 ) -> anyhow::Result<()> {
@@ -719,39 +1572,179 @@ fn reschedule_instance_to(
     .context(with_loc!("Updating table 'instances'"))
 }
 
-fn set_instance_state(tx: &Transaction, id: i64, state: InstanceState) -> anyhow::Result<()> {
+/// A change of the instance's state, and why it happened.
+struct Transition<'a> {
+    from: InstanceState,
+    to: InstanceState,
+    reason: &'a str,
+
+    /// Id of the host that the instance redirects to, when it's "moving" or "moved".
+    redirect_target: Option<i64>,
+}
+
+/// Change the instance's state, and log the change into `state_transitions`. Backoff starts when
+/// the instance becomes "dead" or "moved", and stops when it leaves those states.
+fn set_instance_state(
+    tx: &Connection,
+    id: i64,
+    transition: &Transition,
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let state = transition.to;
     tx.execute(
         "UPDATE instances
         SET state = ?1
         WHERE id = ?2",
         params![state, id],
     )
-    .map(|_| ())
-    .context(with_loc!("Updating table 'instances'"))
+    .context(with_loc!("Updating table 'instances'"))?;
+    tx.execute(
+        "INSERT INTO state_transitions(instance, at, old_state, new_state, reason, redirect_target)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            id,
+            UnixTimestamp(now),
+            transition.from,
+            state,
+            transition.reason,
+            transition.redirect_target
+        ],
+    )
+    .context(with_loc!("Inserting into table 'state_transitions'"))?;
+
+    if state == InstanceState::Dead || state == InstanceState::Moved {
+        tx.execute(
+            "INSERT OR IGNORE
+            INTO backoff_state_data(instance, backoff_since, last_listed_at)
+            VALUES (?1, ?2, ?2)",
+            params![id, UnixTimestamp(now)],
+        )
+        .context(with_loc!("Inserting into table 'backoff_state_data'"))?;
+    } else {
+        tx.execute(
+            "DELETE FROM backoff_state_data
+            WHERE instance = ?1",
+            params![id],
+        )
+        .context(with_loc!("Deleting from table 'backoff_state_data'"))?;
+    }
+    Ok(())
 }
 
-/// Pick the next instance to check, i.e. the one with the smallest `next_check_datetime` value.
-pub fn pick_next_instance(conn: &Connection) -> anyhow::Result<(Domain, SystemTime)> {
-    let (hostname, next_check_datetime): (String, SystemTime) = conn
-        .query_row(
+fn get_backoff_since(tx: &Connection, id: i64) -> anyhow::Result<Option<SystemTime>> {
+    let mut statement = tx
+        .prepare_cached(
+            "SELECT backoff_since
+            FROM backoff_state_data
+            WHERE instance = ?1",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let mut rows = statement
+        .query(params![id])
+        .context(with_loc!("Selecting from table 'backoff_state_data'"))?;
+    match rows.next()? {
+        None => Ok(None),
+        Some(row) => {
+            let since: UnixTimestamp = row.get(0)?;
+            Ok(Some(since.0))
+        }
+    }
+}
+
+/// Note that the instance was seen in a peers list.
+///
+/// Peers lists keep the hosts long after they're gone, so a "dead" or "moved" instance is listed
+/// every day, and that alone says nothing. Its backoff only starts over if it reappears in the
+/// lists after being absent for `UNLISTED_PERIOD_SECONDS`; it's then checked within the usual
+/// period for its state.
+pub fn note_seen_in_peers(
+    conn: &Connection,
+    instance: &Domain,
+    policy: &StatePolicy,
+    env: Env,
+) -> anyhow::Result<()> {
+    let now = env.now();
+    let unlisted_since = now
+        .checked_sub(Duration::from_secs(UNLISTED_PERIOD_SECONDS))
+        .unwrap_or(UNIX_EPOCH);
+    let reset = conn
+        .prepare_cached(
+            "UPDATE backoff_state_data
+            SET backoff_since = ?2
+            WHERE instance = (SELECT id FROM instances WHERE hostname = ?1)
+                AND last_listed_at <= ?3",
+        )
+        .context(with_loc!("Preparing an UPDATE"))?
+        .execute(params![
+            instance.to_string(),
+            UnixTimestamp(now),
+            UnixTimestamp(unlisted_since)
+        ])
+        .context(with_loc!("Updating table 'backoff_state_data'"))?;
+    conn.prepare_cached(
+        "UPDATE backoff_state_data
+        SET last_listed_at = ?2
+        WHERE instance = (SELECT id FROM instances WHERE hostname = ?1)",
+    )
+    .context(with_loc!("Preparing an UPDATE"))?
+    .execute(params![instance.to_string(), UnixTimestamp(now)])
+    .context(with_loc!("Updating table 'backoff_state_data'"))?;
+    if reset == 0 {
+        return Ok(());
+    }
+
+    let (instance_id, state) =
+        get_instance(conn, instance).context(with_loc!("Getting instance id and state"))?;
+    let next_check = policy
+        .check_periods
+        .of(state)
+        .next_check(env)
+        .context(with_loc!("Picking next check's datetime"))?;
+    conn.execute(
+        "UPDATE instances
+        SET next_check_datetime = ?1
+        WHERE id = ?2
+            AND next_check_datetime > ?1",
+        params![UnixTimestamp(next_check), instance_id],
+    )
+    .context(with_loc!("Updating table 'instances'"))?;
+    Ok(())
+}
+
+/// Get at most `limit` instances that are due to be checked by `until`, the earliest first.
+pub fn pick_due_instances(
+    conn: &Connection,
+    until: SystemTime,
+    limit: usize,
+) -> anyhow::Result<Vec<(Domain, SystemTime)>> {
+    let mut statement = conn
+        .prepare_cached(
             "SELECT hostname, next_check_datetime
             FROM instances
+            WHERE next_check_datetime <= ?1
             ORDER BY next_check_datetime ASC
-            LIMIT 1",
-            [],
-            |row| {
-                let hostname = row.get(0)?;
-                let next_check_datetime: UnixTimestamp = row.get(1)?;
-                Ok((hostname, next_check_datetime.0))
-            },
+            LIMIT ?2",
         )
-        .context(with_loc!("Picking next instance"))?;
-    let domain = Domain::from_str(&hostname)?;
-    Ok((domain, next_check_datetime))
+        .context(with_loc!("Preparing a SELECT"))?;
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    let rows = statement
+        .query_map(params![UnixTimestamp(until), limit], |row| {
+            let hostname: String = row.get(0)?;
+            let next_check_datetime: UnixTimestamp = row.get(1)?;
+            Ok((hostname, next_check_datetime.0))
+        })
+        .context(with_loc!("Picking due instances"))?;
+
+    let mut result = vec![];
+    for row in rows {
+        let (hostname, next_check_datetime) = row?;
+        result.push((Domain::from_str(&hostname)?, next_check_datetime));
+    }
+    Ok(result)
 }
 
 fn set_hide_instance_from_list(
-    tx: &Transaction,
+    tx: &Connection,
     instance: i64,
     hide_from_list: bool,
 ) -> anyhow::Result<()> {
@@ -764,7 +1757,7 @@ fn set_hide_instance_from_list(
     Ok(())
 }
 
-fn delete_from_hidden_instances(tx: &Transaction, instance: i64) -> anyhow::Result<()> {
+fn delete_from_hidden_instances(tx: &Connection, instance: i64) -> anyhow::Result<()> {
     tx.execute(
         "DELETE FROM hidden_instances
         WHERE instance = ?1",
@@ -772,3 +1765,1335 @@ fn delete_from_hidden_instances(tx: &Transaction, instance: i64) -> anyhow::Resu
     )?;
     Ok(())
 }
+
+/// Count instances in each state.
+pub fn count_instances_by_state(conn: &Connection) -> anyhow::Result<Vec<(InstanceState, u64)>> {
+    let mut statement = conn
+        .prepare(
+            "SELECT state, count(id)
+            FROM instances
+            GROUP BY state
+            ORDER BY state ASC",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let counts = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .context(with_loc!("Counting instances by state"))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(counts)
+}
+
+/// Count instances in the given state by the reason of their latest failure.
+pub fn count_failure_reasons(
+    conn: &Connection,
+    state: InstanceState,
+) -> anyhow::Result<Vec<(FailureKind, u64)>> {
+    let mut statement = conn
+        .prepare(
+            "SELECT failure_reasons.kind, failure_reasons.http_status, count(instances.id)
+            FROM instances
+                JOIN failure_reasons ON instances.id = failure_reasons.instance
+            WHERE instances.state = ?1
+            GROUP BY failure_reasons.kind, failure_reasons.http_status
+            ORDER BY count(instances.id) DESC",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let counts = statement
+        .query_map(params![state], |row| {
+            let kind = failure_kind_from_sql(row.get(0)?, row.get(1)?).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    0,
+                    rusqlite::types::Type::Integer,
+                    Box::new(e),
+                )
+            })?;
+            Ok((kind, row.get(2)?))
+        })
+        .context(with_loc!("Counting failure reasons"))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(counts)
+}
+
+/// Add the check to the instance's history, and forget the checks that are older than
+/// `retention`.
+pub fn record_check(
+    conn: &mut Connection,
+    instance: &Domain,
+    record: &CheckRecord,
+    retention: Duration,
+    env: Env,
+) -> anyhow::Result<()> {
+    let tx = conn
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
+
+    let now = env.now();
+    let duration_ms = i64::try_from(record.duration.as_millis()).unwrap_or(i64::MAX);
+    tx.execute(
+        "INSERT INTO check_history(instance, checked_at, outcome, duration_ms, peers_count)
+        SELECT id, ?2, ?3, ?4, ?5
+        FROM instances
+        WHERE hostname = ?1",
+        params![
+            instance.to_string(),
+            UnixTimestamp(now),
+            record.outcome,
+            duration_ms,
+            record.peers_count
+        ],
+    )
+    .context(with_loc!("Inserting into table 'check_history'"))?;
+
+    let cutoff = now.checked_sub(retention).unwrap_or(UNIX_EPOCH);
+    tx.execute(
+        "DELETE FROM check_history WHERE checked_at < ?1",
+        params![UnixTimestamp(cutoff)],
+    )
+    .context(with_loc!("Deleting old checks from table 'check_history'"))?;
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+/// The start of each period that `Uptime` covers.
+fn uptime_periods(env: Env) -> [UnixTimestamp; 3] {
+    let days_ago = |days: u64| {
+        UnixTimestamp(
+            env.now()
+                .checked_sub(Duration::from_secs(days.saturating_mul(24 * 60 * 60)))
+                .unwrap_or(UNIX_EPOCH),
+        )
+    };
+    [days_ago(7), days_ago(30), days_ago(90)]
+}
+
+/// Uptime of every instance in the database, along with its hostname and state. Instances are
+/// sorted by hostname.
+pub fn get_uptimes(
+    conn: &Connection,
+    env: Env,
+) -> anyhow::Result<Vec<(String, InstanceState, Uptime)>> {
+    let [week, month, quarter] = uptime_periods(env);
+    // `check_history.instance` has no type affinity, so comparing it to `instances.id` as is would
+    // convert it to an integer first, and SQLite wouldn't look it up in the index. The unary plus
+    // strips the affinity from `instances.id` instead.
+    let mut statement = conn
+        .prepare(
+            "SELECT instances.hostname,
+                instances.state,
+                round(100.0 * total(outcome = ?4 AND checked_at >= ?1)
+                    / nullif(total(checked_at >= ?1), 0), 1),
+                round(100.0 * total(outcome = ?4 AND checked_at >= ?2)
+                    / nullif(total(checked_at >= ?2), 0), 1),
+                round(100.0 * total(outcome = ?4 AND checked_at >= ?3)
+                    / nullif(total(checked_at >= ?3), 0), 1)
+            FROM instances
+                LEFT JOIN check_history
+                    ON check_history.instance = +instances.id
+                    AND check_history.outcome != ?5
+            GROUP BY instances.id
+            ORDER BY instances.hostname",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let uptimes = statement
+        .query_map(
+            params![
+                week,
+                month,
+                quarter,
+                CheckOutcome::Alive,
+                CheckOutcome::Inconclusive
+            ],
+            |row| {
+                let uptime = Uptime {
+                    last_7_days: row.get(2)?,
+                    last_30_days: row.get(3)?,
+                    last_90_days: row.get(4)?,
+                };
+                Ok((row.get(0)?, row.get(1)?, uptime))
+            },
+        )
+        .context(with_loc!("Calculating uptime of each instance"))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(uptimes)
+}
+
+/// Uptime of all instances taken together, i.e. the share of all checks that found an instance
+/// alive.
+pub fn count_uptime(conn: &Connection, env: Env) -> anyhow::Result<Uptime> {
+    let [week, month, quarter] = uptime_periods(env);
+    conn.query_row(
+        "SELECT round(100.0 * total(outcome = ?4 AND checked_at >= ?1)
+                / nullif(total(checked_at >= ?1), 0), 1),
+            round(100.0 * total(outcome = ?4 AND checked_at >= ?2)
+                / nullif(total(checked_at >= ?2), 0), 1),
+            round(100.0 * total(outcome = ?4 AND checked_at >= ?3)
+                / nullif(total(checked_at >= ?3), 0), 1)
+        FROM check_history
+        WHERE outcome != ?5",
+        params![
+            week,
+            month,
+            quarter,
+            CheckOutcome::Alive,
+            CheckOutcome::Inconclusive
+        ],
+        |row| {
+            Ok(Uptime {
+                last_7_days: row.get(0)?,
+                last_30_days: row.get(1)?,
+                last_90_days: row.get(2)?,
+            })
+        },
+    )
+    .context(with_loc!("Calculating the overall uptime"))
+}
+
+/// A change of an instance's state, as logged in `state_transitions`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StateTransition {
+    pub hostname: String,
+
+    /// When the change happened, in UTC, formatted as "YYYY-MM-DD HH:MM:SS".
+    pub at: String,
+
+    pub from: InstanceState,
+    pub to: InstanceState,
+    pub reason: String,
+
+    /// The host that the instance redirected to, for changes into "moving" and "moved".
+    pub redirect_target: Option<String>,
+}
+
+/// Which state transitions to look at.
+pub enum TransitionFilter {
+    /// All transitions of the given instance.
+    Instance(Domain),
+
+    /// Transitions of all instances that happened since the first time, but before the second.
+    Between(SystemTime, SystemTime),
+}
+
+/// Get the state transitions that match the filter, the oldest first.
+pub fn get_state_transitions(
+    conn: &Connection,
+    filter: &TransitionFilter,
+) -> anyhow::Result<Vec<StateTransition>> {
+    const SELECT: &str = "SELECT instances.hostname,
+            datetime(state_transitions.at, 'unixepoch'),
+            state_transitions.old_state,
+            state_transitions.new_state,
+            state_transitions.reason,
+            targets.hostname
+        FROM state_transitions
+            JOIN instances ON instances.id = state_transitions.instance
+            LEFT JOIN instances AS targets ON targets.id = state_transitions.redirect_target";
+    let (condition, params): (&str, Vec<Box<dyn ToSql>>) = match filter {
+        TransitionFilter::Instance(instance) => (
+            "WHERE instances.hostname = ?1",
+            vec![Box::new(instance.to_string())],
+        ),
+        TransitionFilter::Between(since, until) => (
+            "WHERE state_transitions.at >= ?1 AND state_transitions.at < ?2",
+            vec![
+                Box::new(UnixTimestamp(*since)),
+                Box::new(UnixTimestamp(*until)),
+            ],
+        ),
+    };
+    let mut statement = conn
+        .prepare(&format!(
+            "{} {} ORDER BY state_transitions.at, state_transitions.id",
+            SELECT, condition
+        ))
+        .context(with_loc!("Preparing a SELECT"))?;
+    let transitions = statement
+        .query_map(rusqlite::params_from_iter(params), |row| {
+            Ok(StateTransition {
+                hostname: row.get(0)?,
+                at: row.get(1)?,
+                from: row.get(2)?,
+                to: row.get(3)?,
+                reason: row.get(4)?,
+                redirect_target: row.get(5)?,
+            })
+        })
+        .context(with_loc!("Selecting from 'state_transitions'"))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(transitions)
+}
+
+/// Parse a date and time in UTC, in one of the formats that SQLite understands: "YYYY-MM-DD",
+/// "YYYY-MM-DD HH:MM", "YYYY-MM-DD HH:MM:SS", or "now".
+pub fn parse_datetime(conn: &Connection, text: &str) -> anyhow::Result<SystemTime> {
+    let datetime: Option<UnixTimestamp> = conn
+        .query_row(
+            "SELECT CAST(strftime('%s', ?1) AS INTEGER)",
+            params![text],
+            |row| row.get(0),
+        )
+        .context(with_loc!("Parsing the date"))?;
+    match datetime {
+        Some(datetime) => Ok(datetime.0),
+        None => bail!(
+            "{} is not a date; expected e.g. \"2024-05-01\" or \"2024-05-01 13:30\"",
+            text
+        ),
+    }
+}
+
+/// Get the cached copy of the instance's robots.txt, if there is one.
+pub fn get_robots_txt(conn: &Connection, instance: &Domain) -> anyhow::Result<Option<RobotsTxt>> {
+    let mut statement = conn
+        .prepare_cached(
+            "SELECT body, status, fetched_at
+            FROM robots_txt
+                JOIN instances ON instances.id = robots_txt.instance
+            WHERE instances.hostname = ?1",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let mut rows = statement
+        .query(params![instance.to_string()])
+        .context(with_loc!("Selecting from 'robots_txt'"))?;
+    match rows.next()? {
+        None => Ok(None),
+        Some(row) => {
+            let fetched_at: UnixTimestamp = row.get(2)?;
+            Ok(Some(RobotsTxt {
+                body: row.get(0)?,
+                status: row.get(1)?,
+                fetched_at: fetched_at.0,
+            }))
+        }
+    }
+}
+
+/// Returns `true` if the checker should treat unreachable robots.txt as allowing everything.
+///
+/// That's the case once robots.txt was unavailable for `FailurePolicy::robots_txt_unavailable_checks`
+/// checks in a row, and also for "dying" and "dead" instances: those already failed a proper check,
+/// and the next one should count towards their death rather than stall on robots.txt.
+pub fn is_robots_txt_long_unavailable(
+    conn: &Connection,
+    instance: &Domain,
+    policy: &FailurePolicy,
+) -> anyhow::Result<bool> {
+    let (state, kind, repeats): (InstanceState, Option<i64>, Option<u64>) = conn
+        .prepare_cached(
+            "SELECT instances.state, failure_reasons.kind, failure_reasons.repeats
+            FROM instances
+                LEFT JOIN failure_reasons ON instances.id = failure_reasons.instance
+            WHERE instances.hostname = ?1",
+        )
+        .context(with_loc!("Preparing a SELECT"))?
+        .query_row(params![instance.to_string()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .context(with_loc!("Selecting from 'failure_reasons'"))?;
+    if state == InstanceState::Dying || state == InstanceState::Dead {
+        return Ok(true);
+    }
+    let (unavailable, _) = failure_kind_to_sql(FailureKind::RobotsTxtUnavailable);
+    Ok(kind == Some(unavailable) && repeats.unwrap_or(0) >= policy.robots_txt_unavailable_checks)
+}
+
+/// Replace the cached copy of the instance's robots.txt.
+pub fn set_robots_txt(
+    conn: &Connection,
+    instance: &Domain,
+    robots_txt: &RobotsTxt,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE
+        INTO robots_txt(instance, body, status, fetched_at)
+        SELECT id, ?2, ?3, ?4
+        FROM instances
+        WHERE hostname = ?1",
+        params![
+            instance.to_string(),
+            robots_txt.body,
+            robots_txt.status,
+            UnixTimestamp(robots_txt.fetched_at)
+        ],
+    )
+    .map(|_| ())
+    .context(with_loc!("Inserting into table 'robots_txt'"))
+}
+
+/// Get the validators that the instance sent during previous checks, per endpoint.
+pub fn get_validators(
+    conn: &Connection,
+    instance: &Domain,
+) -> anyhow::Result<HashMap<Endpoint, Validators>> {
+    let mut statement = conn
+        .prepare_cached(
+            "SELECT endpoint, etag, last_modified
+            FROM http_validators
+                JOIN instances ON instances.id = http_validators.instance
+            WHERE instances.hostname = ?1",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let mut rows = statement
+        .query(params![instance.to_string()])
+        .context(with_loc!("Selecting from 'http_validators'"))?;
+    let mut result = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let endpoint = endpoint_from_sql(row.get(0)?)?;
+        let validators = Validators {
+            etag: row.get(1)?,
+            last_modified: row.get(2)?,
+        };
+        result.insert(endpoint, validators);
+    }
+    Ok(result)
+}
+
+/// Remember the validators for the instance's endpoint. Empty validators erase the old ones.
+pub fn set_validators(
+    conn: &Connection,
+    instance: &Domain,
+    endpoint: Endpoint,
+    validators: &Validators,
+) -> anyhow::Result<()> {
+    if validators.is_empty() {
+        return conn
+            .execute(
+                "DELETE FROM http_validators
+                WHERE endpoint = ?2
+                    AND instance = (SELECT id FROM instances WHERE hostname = ?1)",
+                params![instance.to_string(), endpoint_to_sql(endpoint)],
+            )
+            .map(|_| ())
+            .context(with_loc!("Deleting from table 'http_validators'"));
+    }
+
+    conn.execute(
+        "INSERT OR REPLACE
+        INTO http_validators(instance, endpoint, etag, last_modified)
+        SELECT id, ?2, ?3, ?4
+        FROM instances
+        WHERE hostname = ?1",
+        params![
+            instance.to_string(),
+            endpoint_to_sql(endpoint),
+            validators.etag,
+            validators.last_modified
+        ],
+    )
+    .map(|_| ())
+    .context(with_loc!("Inserting into table 'http_validators'"))
+}
+
+/// Get the software that the instance ran during the last successful check.
+pub fn get_software(conn: &Connection, instance: &Domain) -> anyhow::Result<Option<String>> {
+    let mut statement = conn
+        .prepare_cached(
+            "SELECT software
+            FROM instance_software
+                JOIN instances ON instances.id = instance_software.instance
+            WHERE instances.hostname = ?1",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let mut rows = statement
+        .query(params![instance.to_string()])
+        .context(with_loc!("Selecting from 'instance_software'"))?;
+    match rows.next()? {
+        None => Ok(None),
+        Some(row) => Ok(Some(row.get(0)?)),
+    }
+}
+
+/// Remember the software that the instance runs.
+pub fn set_software(conn: &Connection, instance: &Domain, software: &str) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE
+        INTO instance_software(instance, software)
+        SELECT id, ?2
+        FROM instances
+        WHERE hostname = ?1",
+        params![instance.to_string(), software],
+    )
+    .map(|_| ())
+    .context(with_loc!("Inserting into table 'instance_software'"))
+}
+
+/// A group of instances that share a registrable domain, and is large enough to need moderation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModerationGroup {
+    pub registrable_domain: String,
+    pub decision: ModerationDecision,
+
+    /// How many hosts of this group were held out of the list last time it was generated.
+    pub held: u64,
+}
+
+/// Get all the moderation groups, largest first.
+pub fn get_moderation_groups(conn: &Connection) -> anyhow::Result<Vec<ModerationGroup>> {
+    let mut statement = conn
+        .prepare(
+            "SELECT registrable_domain, decision, held
+            FROM moderation_groups
+            ORDER BY held DESC, registrable_domain ASC",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let groups = statement
+        .query_map([], |row| {
+            Ok(ModerationGroup {
+                registrable_domain: row.get(0)?,
+                decision: row.get(1)?,
+                held: row.get(2)?,
+            })
+        })
+        .context(with_loc!("Selecting from 'moderation_groups'"))?
+        .collect::<Result<_, _>>()
+        .context(with_loc!("Reading rows of 'moderation_groups'"))?;
+    Ok(groups)
+}
+
+/// Record how many hosts each group keeps out of the list, given as registrable domain and the
+/// number of held hosts.
+///
+/// Groups that aren't mentioned hold nothing anymore: pending ones are deleted, and the ones that
+/// were decided on are kept along with their decisions. Mentioned groups that weren't decided on
+/// yet become pending.
+pub fn update_moderation_groups(
+    conn: &mut Connection,
+    held: &[(String, u64)],
+) -> anyhow::Result<()> {
+    let tx = conn
+        .savepoint()
+        .context(with_loc!("Creating a savepoint"))?;
+
+    tx.execute(
+        "DELETE FROM moderation_groups
+        WHERE decision = ?1",
+        params![ModerationDecision::Pending],
+    )
+    .context(with_loc!("Deleting from table 'moderation_groups'"))?;
+    tx.execute("UPDATE moderation_groups SET held = 0", [])
+        .context(with_loc!("Updating table 'moderation_groups'"))?;
+    {
+        let mut statement = tx
+            .prepare(
+                "INSERT
+                INTO moderation_groups(registrable_domain, decision, held)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT(registrable_domain) DO UPDATE
+                SET held = excluded.held",
+            )
+            .context(with_loc!("Preparing an INSERT"))?;
+        for (registrable_domain, held) in held {
+            statement
+                .execute(params![
+                    registrable_domain,
+                    ModerationDecision::Pending,
+                    held
+                ])
+                .context(with_loc!("Upserting into table 'moderation_groups'"))?;
+        }
+    }
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+/// Record the moderator's decision about the group. The group doesn't have to be pending: one can
+/// decide on a domain before it grows large.
+pub fn set_moderation_decision(
+    conn: &Connection,
+    registrable_domain: &str,
+    decision: ModerationDecision,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "INSERT
+        INTO moderation_groups(registrable_domain, decision)
+        VALUES (?1, ?2)
+        ON CONFLICT(registrable_domain) DO UPDATE
+        SET decision = excluded.decision",
+        params![registrable_domain, decision],
+    )
+    .map(|_| ())
+    .context(with_loc!("Upserting into table 'moderation_groups'"))
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failure_kinds_survive_a_roundtrip_through_sql() {
+        let kinds = [
+            FailureKind::Nxdomain,
+            FailureKind::DnsError,
+            FailureKind::ConnectionRefused,
+            FailureKind::ConnectionFailed,
+            FailureKind::Tls,
+            FailureKind::Timeout,
+            FailureKind::HttpStatus { status: 503 },
+            FailureKind::ForbiddenByRobotsTxt,
+            FailureKind::InvalidNodeInfo,
+            FailureKind::CrossOriginRedirect,
+            FailureKind::TemporaryRedirect,
+            FailureKind::InvalidRedirect,
+            FailureKind::NoResponse,
+            FailureKind::Other,
+            FailureKind::RobotsTxtUnavailable,
+            FailureKind::ForbiddenAddress,
+            FailureKind::CheckTimeBudgetExceeded,
+        ];
+        let mut conn = Connection::open_in_memory().unwrap();
+        init(&mut conn).unwrap();
+        for kind in kinds {
+            let (id, http_status) = failure_kind_to_sql(kind);
+            assert_eq!(failure_kind_from_sql(id, http_status).unwrap(), kind);
+            let known: bool = conn
+                .query_row(
+                    "SELECT count(*) > 0 FROM failure_kinds WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert!(known, "{:?}", kind);
+        }
+    }
+
+    /// An in-memory database with a single instance in it.
+    fn database_with(hostname: &str) -> (Connection, Domain) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init(&mut conn).unwrap();
+        let instance = Domain::from_str(hostname).unwrap();
+        add_instance(&conn, &instance, Env::system()).unwrap();
+        (conn, instance)
+    }
+
+    fn state_of(conn: &Connection, instance: &Domain) -> InstanceState {
+        conn.query_row(
+            "SELECT state FROM instances WHERE hostname = ?1",
+            params![instance.to_string()],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn requeues_checks_that_were_in_flight() {
+        let (mut conn, instance) = database_with("example.com");
+        let finished = Domain::from_str("finished.example.com").unwrap();
+        add_instance(&conn, &finished, Env::system()).unwrap();
+
+        start_checks(
+            &mut conn,
+            &[instance.clone(), finished.clone()],
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        finish_check(&conn, &finished).unwrap();
+        let next_check = |conn: &Connection, instance: &Domain| -> UnixTimestamp {
+            conn.query_row(
+                "SELECT next_check_datetime FROM instances WHERE hostname = ?1",
+                params![instance.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let soon = time::in_about_a_minute(Env::system()).unwrap() + Duration::from_secs(60);
+        assert!(next_check(&conn, &instance).0 > soon);
+
+        assert_eq!(
+            requeue_in_flight_checks(&mut conn, Env::system()).unwrap(),
+            1
+        );
+        assert!(next_check(&conn, &instance).0 <= soon);
+        assert!(next_check(&conn, &finished).0 > soon);
+
+        assert_eq!(
+            requeue_in_flight_checks(&mut conn, Env::system()).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn records_latest_failure_reason() {
+        let (mut conn, instance) = database_with("example.com");
+
+        let policy = FailurePolicy::default();
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Timeout,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::HttpStatus { status: 502 },
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(
+            count_failure_reasons(&conn, InstanceState::Dying).unwrap(),
+            vec![(FailureKind::HttpStatus { status: 502 }, 1)]
+        );
+
+        mark_alive(
+            &mut conn,
+            &instance,
+            false,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert!(count_failure_reasons(&conn, InstanceState::Alive)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn init_adds_missing_columns() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE failure_reasons(
+                id INTEGER PRIMARY KEY NOT NULL,
+                instance INTEGER NOT NULL UNIQUE,
+                kind INTEGER NOT NULL,
+                http_status INTEGER,
+                failed_at INTEGER NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        init(&mut conn).unwrap();
+
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance, Env::system()).unwrap();
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Timeout,
+            &FailurePolicy::default(),
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn never_alive_nxdomain_twice_is_dead() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = FailurePolicy::default();
+
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Nxdomain,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dying);
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Nxdomain,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dead);
+    }
+
+    #[test]
+    fn only_consecutive_failures_count_towards_the_fast_path() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = FailurePolicy::default();
+
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Nxdomain,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Timeout,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Nxdomain,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dying);
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Nxdomain,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dead);
+    }
+
+    #[test]
+    fn previously_alive_instances_keep_the_grace_period() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = FailurePolicy::default();
+        mark_alive(
+            &mut conn,
+            &instance,
+            false,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+
+        for reason in [
+            FailureKind::Nxdomain,
+            FailureKind::Tls,
+            FailureKind::HttpStatus { status: 503 },
+        ] {
+            for _ in 0..10 {
+                mark_dead(
+                    &mut conn,
+                    &instance,
+                    reason,
+                    &policy,
+                    &StatePolicy::default(),
+                    Env::system(),
+                )
+                .unwrap();
+                assert_eq!(state_of(&conn, &instance), InstanceState::Dying);
+            }
+        }
+    }
+
+    #[test]
+    fn never_alive_instances_keep_the_grace_period_for_other_failures() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = FailurePolicy::default();
+
+        for _ in 0..10 {
+            mark_dead(
+                &mut conn,
+                &instance,
+                FailureKind::Tls,
+                &policy,
+                &StatePolicy::default(),
+                Env::system(),
+            )
+            .unwrap();
+            assert_eq!(state_of(&conn, &instance), InstanceState::Dying);
+        }
+    }
+
+    #[test]
+    fn grace_period_is_configurable() {
+        let (mut conn, instance) = database_with("example.com");
+        let failure_policy = FailurePolicy::default();
+        let state_policy = StatePolicy {
+            dying_grace_period_seconds: 0,
+            dying_failed_checks: 3,
+            ..StatePolicy::default()
+        };
+        mark_alive(&mut conn, &instance, false, &state_policy, Env::system()).unwrap();
+
+        for _ in 0..2 {
+            mark_dead(
+                &mut conn,
+                &instance,
+                FailureKind::Tls,
+                &failure_policy,
+                &state_policy,
+                Env::system(),
+            )
+            .unwrap();
+            assert_eq!(state_of(&conn, &instance), InstanceState::Dying);
+        }
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Tls,
+            &failure_policy,
+            &state_policy,
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dead);
+    }
+
+    #[test]
+    fn dead_instances_back_off_until_seen_in_peers() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = StatePolicy {
+            dying_grace_period_seconds: 0,
+            dying_failed_checks: 1,
+            ..StatePolicy::default()
+        };
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Tls,
+            &FailurePolicy::default(),
+            &policy,
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dead);
+
+        let days_from_now = |days: u64| {
+            SystemTime::now()
+                .checked_add(Duration::from_secs(days * 24 * 60 * 60))
+                .unwrap()
+        };
+        let next_check = |conn: &Connection| -> SystemTime {
+            let next_check: UnixTimestamp = conn
+                .query_row(
+                    "SELECT next_check_datetime FROM instances WHERE hostname = ?1",
+                    params![instance.to_string()],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            next_check.0
+        };
+
+        // Dead for 60 days, so the next check is about 60 days away.
+        conn.execute(
+            "UPDATE backoff_state_data SET backoff_since = ?1",
+            params![UnixTimestamp(
+                SystemTime::now()
+                    .checked_sub(Duration::from_secs(60 * 24 * 60 * 60))
+                    .unwrap()
+            )],
+        )
+        .unwrap();
+        start_checks(
+            &mut conn,
+            std::slice::from_ref(&instance),
+            &policy,
+            Env::system(),
+        )
+        .unwrap();
+        assert!(next_check(&conn) > days_from_now(55));
+        assert!(next_check(&conn) < days_from_now(65));
+
+        // Dead for a year, but the backoff is capped at 90 days. The jitter is stretched along
+        // with the period, to about 6 days.
+        conn.execute("UPDATE backoff_state_data SET backoff_since = 0", params![])
+            .unwrap();
+        start_checks(
+            &mut conn,
+            std::slice::from_ref(&instance),
+            &policy,
+            Env::system(),
+        )
+        .unwrap();
+        assert!(next_check(&conn) > days_from_now(83));
+        assert!(next_check(&conn) < days_from_now(97));
+
+        // Peers lists keep dead hosts around, so being listed again doesn't reset the backoff...
+        note_seen_in_peers(&conn, &instance, &policy, Env::system()).unwrap();
+        assert!(next_check(&conn) > days_from_now(83));
+
+        // ...but reappearing in the lists after a week-long absence does.
+        conn.execute(
+            "UPDATE backoff_state_data SET last_listed_at = 0",
+            params![],
+        )
+        .unwrap();
+        note_seen_in_peers(&conn, &instance, &policy, Env::system()).unwrap();
+        assert!(next_check(&conn) < days_from_now(8));
+
+        mark_alive(&mut conn, &instance, false, &policy, Env::system()).unwrap();
+        assert_eq!(get_backoff_since(&conn, 1).unwrap(), None);
+    }
+
+    #[test]
+    fn state_transitions_are_logged() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = StatePolicy {
+            dying_grace_period_seconds: 0,
+            dying_failed_checks: 2,
+            ..StatePolicy::default()
+        };
+        for _ in 0..2 {
+            mark_dead(
+                &mut conn,
+                &instance,
+                FailureKind::Timeout,
+                &FailurePolicy::default(),
+                &policy,
+                Env::system(),
+            )
+            .unwrap();
+        }
+        mark_alive(&mut conn, &instance, false, &policy, Env::system()).unwrap();
+        // Staying alive isn't a transition.
+        mark_alive(&mut conn, &instance, false, &policy, Env::system()).unwrap();
+        let target = Domain::from_str("new.example.com").unwrap();
+        mark_moved(&mut conn, &instance, &target, &policy, Env::system()).unwrap();
+
+        let transitions =
+            get_state_transitions(&conn, &TransitionFilter::Instance(instance.clone())).unwrap();
+        let transitions: Vec<_> = transitions
+            .iter()
+            .map(|t| {
+                (
+                    t.from,
+                    t.to,
+                    t.reason.as_str(),
+                    t.redirect_target.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            transitions,
+            [
+                (
+                    InstanceState::Discovered,
+                    InstanceState::Dying,
+                    "timeout",
+                    None
+                ),
+                (
+                    InstanceState::Dying,
+                    InstanceState::Dead,
+                    "timeout, and failing for 2 checks",
+                    None
+                ),
+                (
+                    InstanceState::Dead,
+                    InstanceState::Alive,
+                    "responded with valid NodeInfo",
+                    None
+                ),
+                (
+                    InstanceState::Alive,
+                    InstanceState::Moving,
+                    "permanent redirect",
+                    Some("new.example.com")
+                ),
+            ]
+        );
+
+        let minute = Duration::from_secs(60);
+        let a_minute_ago = SystemTime::now().checked_sub(minute).unwrap();
+        let in_a_minute = SystemTime::now().checked_add(minute).unwrap();
+        let between = |since, until| {
+            get_state_transitions(&conn, &TransitionFilter::Between(since, until))
+                .unwrap()
+                .len()
+        };
+        assert_eq!(between(a_minute_ago, in_a_minute), 4);
+        assert_eq!(
+            between(
+                in_a_minute,
+                SystemTime::now().checked_add(minute * 2).unwrap()
+            ),
+            0
+        );
+
+        assert!(conn.execute("DELETE FROM state_transitions", []).is_err());
+        assert!(conn
+            .execute("UPDATE state_transitions SET reason = ''", [])
+            .is_err());
+    }
+
+    #[test]
+    fn parses_dates() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(
+            parse_datetime(&conn, "2024-05-01").unwrap(),
+            UNIX_EPOCH
+                .checked_add(Duration::from_secs(1_714_521_600))
+                .unwrap()
+        );
+        assert_eq!(
+            parse_datetime(&conn, "2024-05-01 13:30").unwrap(),
+            UNIX_EPOCH
+                .checked_add(Duration::from_secs(1_714_570_200))
+                .unwrap()
+        );
+        assert!(parse_datetime(&conn, "yesterday").is_err());
+    }
+
+    #[test]
+    fn fast_path_is_configurable() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = FailurePolicy {
+            never_alive: vec![FailureRule {
+                kind: FailureKind::ConnectionRefused,
+                failures: 1,
+            }],
+            ..FailurePolicy::default()
+        };
+
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Nxdomain,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Nxdomain,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dying);
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::ConnectionRefused,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dead);
+    }
+
+    #[test]
+    fn caches_robots_txt() {
+        let (conn, instance) = database_with("example.com");
+        assert_eq!(get_robots_txt(&conn, &instance).unwrap(), None);
+
+        let robots_txt = RobotsTxt {
+            body: "User-agent: *\nDisallow: /private".to_string(),
+            status: 200,
+            fetched_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        set_robots_txt(&conn, &instance, &robots_txt).unwrap();
+        assert_eq!(get_robots_txt(&conn, &instance).unwrap(), Some(robots_txt));
+
+        let robots_txt = RobotsTxt {
+            body: String::new(),
+            status: 404,
+            fetched_at: UNIX_EPOCH + Duration::from_secs(1_700_100_000),
+        };
+        set_robots_txt(&conn, &instance, &robots_txt).unwrap();
+        assert_eq!(get_robots_txt(&conn, &instance).unwrap(), Some(robots_txt));
+    }
+
+    #[test]
+    fn remembers_validators_and_software() {
+        let (conn, instance) = database_with("example.com");
+        assert!(get_validators(&conn, &instance).unwrap().is_empty());
+        assert_eq!(get_software(&conn, &instance).unwrap(), None);
+
+        let nodeinfo = Validators {
+            etag: Some(r#""abc""#.to_string()),
+            last_modified: None,
+        };
+        let peers = Validators {
+            etag: None,
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        set_validators(&conn, &instance, Endpoint::NodeInfo, &nodeinfo).unwrap();
+        set_validators(&conn, &instance, Endpoint::Peers, &peers).unwrap();
+        set_software(&conn, &instance, "mastodon").unwrap();
+        assert_eq!(
+            get_validators(&conn, &instance).unwrap(),
+            HashMap::from([(Endpoint::NodeInfo, nodeinfo), (Endpoint::Peers, peers)])
+        );
+        assert_eq!(
+            get_software(&conn, &instance).unwrap(),
+            Some("mastodon".to_string())
+        );
+
+        set_validators(&conn, &instance, Endpoint::Peers, &Validators::default()).unwrap();
+        assert_eq!(
+            get_validators(&conn, &instance)
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![Endpoint::NodeInfo]
+        );
+    }
+
+    #[test]
+    fn unavailable_robots_txt_does_not_kill_instances() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = FailurePolicy::default();
+
+        for _ in 0..10 {
+            mark_inconclusive(
+                &mut conn,
+                &instance,
+                FailureKind::RobotsTxtUnavailable,
+                Env::system(),
+            )
+            .unwrap();
+        }
+        assert_eq!(state_of(&conn, &instance), InstanceState::Discovered);
+
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Timeout,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        mark_inconclusive(
+            &mut conn,
+            &instance,
+            FailureKind::RobotsTxtUnavailable,
+            Env::system(),
+        )
+        .unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Dying);
+        assert_eq!(
+            count_failure_reasons(&conn, InstanceState::Dying).unwrap(),
+            vec![(FailureKind::RobotsTxtUnavailable, 1)]
+        );
+    }
+
+    #[test]
+    fn moving_and_moved_instances_can_redirect_to_unknown_hosts() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = StatePolicy::default();
+        let new = Domain::from_str("new.example.com").unwrap();
+        let newer = Domain::from_str("newer.example.com").unwrap();
+        let newest = Domain::from_str("newest.example.com").unwrap();
+
+        mark_moved(&mut conn, &instance, &new, &policy, Env::system()).unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Moving);
+        mark_moved(&mut conn, &instance, &newer, &policy, Env::system()).unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Moving);
+        assert_eq!(state_of(&conn, &newer), InstanceState::Discovered);
+
+        // Pretend it's been redirecting there for a long time
+        conn.execute(
+            "UPDATE moving_state_data SET moving_since = 0, redirects_count = 100",
+            params![],
+        )
+        .unwrap();
+        mark_moved(&mut conn, &instance, &newer, &policy, Env::system()).unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Moved);
+
+        mark_moved(&mut conn, &instance, &newest, &policy, Env::system()).unwrap();
+        assert_eq!(state_of(&conn, &instance), InstanceState::Moving);
+        assert_eq!(state_of(&conn, &newest), InstanceState::Discovered);
+    }
+
+    #[test]
+    fn robots_txt_is_ignored_once_unavailable_for_long() {
+        let (mut conn, instance) = database_with("example.com");
+        let policy = FailurePolicy::default();
+        let long_unavailable =
+            |conn: &Connection| is_robots_txt_long_unavailable(conn, &instance, &policy).unwrap();
+
+        assert!(!long_unavailable(&conn));
+        for _ in 1..policy.robots_txt_unavailable_checks {
+            mark_inconclusive(
+                &mut conn,
+                &instance,
+                FailureKind::RobotsTxtUnavailable,
+                Env::system(),
+            )
+            .unwrap();
+        }
+        assert!(!long_unavailable(&conn));
+        mark_inconclusive(
+            &mut conn,
+            &instance,
+            FailureKind::RobotsTxtUnavailable,
+            Env::system(),
+        )
+        .unwrap();
+        assert!(long_unavailable(&conn));
+
+        // Once the instance is dying, every check counts
+        mark_dead(
+            &mut conn,
+            &instance,
+            FailureKind::Tls,
+            &policy,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        mark_inconclusive(
+            &mut conn,
+            &instance,
+            FailureKind::RobotsTxtUnavailable,
+            Env::system(),
+        )
+        .unwrap();
+        assert!(long_unavailable(&conn));
+
+        mark_alive(
+            &mut conn,
+            &instance,
+            false,
+            &StatePolicy::default(),
+            Env::system(),
+        )
+        .unwrap();
+        assert!(!long_unavailable(&conn));
+    }
+
+    #[test]
+    fn moderation_decisions_outlive_pending_groups() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init(&mut conn).unwrap();
+
+        update_moderation_groups(
+            &mut conn,
+            &[
+                ("example.com".to_string(), 3),
+                ("example.org".to_string(), 5),
+            ],
+        )
+        .unwrap();
+        set_moderation_decision(&conn, "example.com", ModerationDecision::Approved).unwrap();
+        set_moderation_decision(&conn, "example.net", ModerationDecision::Rejected).unwrap();
+
+        // On the next list generation, example.org shrunk below the threshold, example.com is
+        // approved so it holds nothing back, and example.net is rejected so all its hosts are held.
+        update_moderation_groups(&mut conn, &[("example.net".to_string(), 2)]).unwrap();
+
+        assert_eq!(
+            get_moderation_groups(&conn).unwrap(),
+            vec![
+                ModerationGroup {
+                    registrable_domain: "example.net".to_string(),
+                    decision: ModerationDecision::Rejected,
+                    held: 2,
+                },
+                ModerationGroup {
+                    registrable_domain: "example.com".to_string(),
+                    decision: ModerationDecision::Approved,
+                    held: 0,
+                },
+            ]
+        );
+    }
+}
@@ -2,7 +2,7 @@
 use anyhow::bail;
 use url::Host;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 /// A domain name with a suffix known to the Public Suffix List.
 pub struct Domain {
     domain: String,
@@ -28,6 +28,17 @@ impl Domain {
         Ok(Self { domain })
     }
 
+    /// The registrable part of the domain, e.g. "example.co.uk" for "foo.bar.example.co.uk".
+    ///
+    /// This is the part that one person or organization controls. If the domain *is* a public
+    /// suffix, the whole domain is returned.
+    pub fn registrable_domain(&self) -> &str {
+        addr::parse_domain_name(&self.domain)
+            .ok()
+            .and_then(|name| name.root())
+            .unwrap_or(&self.domain)
+    }
+
     /// Construct from [`url::Host::Domain`].
     pub fn from_host(host: &Host) -> anyhow::Result<Self> {
         match host {
@@ -49,6 +60,20 @@ impl std::fmt::Display for Domain {
 mod test {
     use super::*;
 
+    #[test]
+    fn finds_registrable_domain() {
+        let registrable = |domain: &str| {
+            Domain::from_str(domain)
+                .unwrap()
+                .registrable_domain()
+                .to_owned()
+        };
+        assert_eq!(registrable("example.com"), "example.com");
+        assert_eq!(registrable("foo.bar.example.com"), "example.com");
+        assert_eq!(registrable("mastodon.example.co.uk"), "example.co.uk");
+        assert_eq!(registrable("co.uk"), "co.uk");
+    }
+
     #[test]
     fn accepts_only_host_domain() {
         use url::Host;
@@ -0,0 +1,40 @@
+//! Print a JSON array of all instances in the database, with their states and uptime.
+use crate::db;
+use crate::time::Env;
+use crate::with_loc;
+use anyhow::Context;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Instance {
+    hostname: String,
+    state: String,
+
+    /// Share of the checks that found the instance alive, in percent; `null` if there were no
+    /// checks in that period.
+    uptime_7_days: Option<f64>,
+    uptime_30_days: Option<f64>,
+    uptime_90_days: Option<f64>,
+}
+
+pub fn main() -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+
+    let instances: Vec<Instance> = db::get_uptimes(&conn, Env::system())?
+        .into_iter()
+        .map(|(hostname, state, uptime)| Instance {
+            hostname,
+            state: state.to_string(),
+            uptime_7_days: uptime.last_7_days,
+            uptime_30_days: uptime.last_30_days,
+            uptime_90_days: uptime.last_90_days,
+        })
+        .collect();
+
+    let stdout = std::io::stdout().lock();
+    serde_json::to_writer(stdout, &instances).context(with_loc!("Writing the instances"))?;
+    println!();
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use crate::{db, domain::Domain};
+use crate::{db, domain::Domain, time::Env};
 use slog::{error, info, Logger};
 use std::io::{self, BufRead};
 
@@ -25,7 +25,9 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
 
             Ok(domain) => domain,
         };
-        match db::on_sqlite_busy_retry_indefinitely(&mut || db::add_instance(&conn, &domain)) {
+        match db::on_sqlite_busy_retry_indefinitely(&mut || {
+            db::add_instance(&conn, &domain, Env::system())
+        }) {
             Err(e) => {
                 let msg = format!("Failed to add {} to the database: {}", domain, e);
                 error!(logger, "{}", msg);
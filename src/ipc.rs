@@ -1,6 +1,29 @@
+use crate::with_loc;
+use anyhow::{anyhow, bail, Context};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, SystemTime};
 use url::Host;
 
+/// Version of the protocol spoken between the orchestrator and the checker.
+///
+/// The orchestrator and checkers are the same executable, but a deploy can replace the file while
+/// the orchestrator is running. Bump this whenever `CheckerResponse` changes.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// Maximum length of a single message, including the terminating newline.
+///
+/// This has to fit a `RobotsTxt` message, which is why it's larger than any other message needs.
+pub const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// Maximum length of the `CheckerRequest` that the orchestrator sends to the checker.
+pub const MAX_REQUEST_LEN: u64 = 1024 * 1024;
+
+/// How often the checker sends a heartbeat while it's working.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum InstanceState {
     /// The instance is alive (it responded with a valid NodeInfo document).
@@ -16,12 +39,403 @@ pub enum InstanceState {
     Moved { to: Host },
 }
 
+/// A copy of robots.txt.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RobotsTxt {
+    /// Contents of the file.
+    pub body: String,
+
+    /// HTTP status with which the file was served.
+    pub status: u16,
+
+    /// When the file was fetched.
+    pub fetched_at: SystemTime,
+}
+
+/// An endpoint whose responses the checker can validate with conditional requests.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Endpoint {
+    /// The NodeInfo document (not the well-known pointer to it).
+    NodeInfo,
+
+    /// The list of peers.
+    Peers,
+}
+
+/// Values of the `ETag` and `Last-Modified` headers, which let the server tell us that a resource
+/// didn't change since the last time we fetched it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Returns `true` if there's nothing to validate with.
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// A rule saying that requests to some hosts should go through a proxy.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRoute {
+    /// Domain suffix, e.g. ".onion". It matches the domain itself and all of its subdomains; an
+    /// empty suffix matches every host.
+    pub suffix: String,
+
+    /// The proxy, e.g. "socks5://127.0.0.1:9050". See `ureq::Proxy::new()` for the format.
+    pub proxy: String,
+}
+
+impl ProxyRoute {
+    /// Returns `true` if requests to `host` should go through this proxy.
+    pub fn matches(&self, host: &str) -> bool {
+        let suffix = self.suffix.trim_start_matches('.').to_ascii_lowercase();
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        suffix.is_empty()
+            || host == suffix
+            || host
+                .strip_suffix(&suffix)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    }
+}
+
+/// Data that the orchestrator passes to the checker via the checker's stdin.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct CheckerRequest {
+    /// robots.txt fetched by an earlier check, if any.
+    pub robots_txt: Option<RobotsTxt>,
+
+    /// If robots.txt can't be fetched because of a server or network error, and there's no cached
+    /// copy, treat it as allowing everything instead of giving up on the check.
+    #[serde(default)]
+    pub ignore_unreachable_robots_txt: bool,
+
+    /// Proxies to use. The first matching route wins; hosts that match none are contacted
+    /// directly.
+    #[serde(default)]
+    pub proxies: Vec<ProxyRoute>,
+
+    /// Whether the checker may connect to private, loopback, and other non-global addresses.
+    #[serde(default)]
+    pub allow_private_addresses: bool,
+
+    /// Validators from the previous successful check, per endpoint.
+    #[serde(default)]
+    pub validators: HashMap<Endpoint, Validators>,
+
+    /// The software that the instance ran during the previous successful check. If the NodeInfo
+    /// document didn't change, this is what the instance still runs.
+    #[serde(default)]
+    pub software: Option<String>,
+}
+
+/// The reason why a check failed.
+///
+/// Most of these are determined by the checker, but the orchestrator uses this too, to record the
+/// failures that it detects by itself.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum FailureKind {
+    /// The hostname doesn't exist (DNS responded with NXDOMAIN or no addresses).
+    Nxdomain,
+
+    /// DNS lookup failed for some other reason, e.g. a timeout.
+    DnsError,
+
+    /// The host resolves only to addresses that we don't connect to, e.g. private or loopback
+    /// ones.
+    ForbiddenAddress,
+
+    /// The host refused the connection.
+    ConnectionRefused,
+
+    /// Couldn't connect to the host for some other reason, e.g. the network is unreachable.
+    ConnectionFailed,
+
+    /// TLS handshake failed, e.g. because of an invalid certificate.
+    Tls,
+
+    /// The host didn't respond in time.
+    Timeout,
+
+    /// The instance responded with an HTTP error.
+    HttpStatus { status: u16 },
+
+    /// robots.txt forbids us from accessing the instance.
+    ForbiddenByRobotsTxt,
+
+    /// robots.txt couldn't be fetched because of a server error or a timeout, so we aren't allowed
+    /// to access anything. This doesn't say anything about whether the instance is alive.
+    RobotsTxtUnavailable,
+
+    /// `Crawl-delay` made us wait so long between requests that the check ran out of time before
+    /// it could tell whether the instance is alive.
+    CheckTimeBudgetExceeded,
+
+    /// The instance doesn't serve a valid NodeInfo document.
+    InvalidNodeInfo,
+
+    /// The instance redirected us to another origin when it wasn't supposed to.
+    CrossOriginRedirect,
+
+    /// The instance responded with a temporary redirect. Determined by the orchestrator.
+    TemporaryRedirect,
+
+    /// The instance responded with a permanent redirect to itself, or to something that isn't
+    /// a valid domain name. Determined by the orchestrator.
+    InvalidRedirect,
+
+    /// The checker terminated without reporting anything. Determined by the orchestrator.
+    NoResponse,
+
+    /// Something else went wrong.
+    Other,
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailureKind::Nxdomain => write!(f, "NXDOMAIN"),
+            FailureKind::DnsError => write!(f, "DNS error"),
+            FailureKind::ForbiddenAddress => write!(f, "forbidden address"),
+            FailureKind::ConnectionRefused => write!(f, "connection refused"),
+            FailureKind::ConnectionFailed => write!(f, "connection failed"),
+            FailureKind::Tls => write!(f, "TLS error"),
+            FailureKind::Timeout => write!(f, "timeout"),
+            FailureKind::HttpStatus { status } => write!(f, "HTTP {}", status),
+            FailureKind::ForbiddenByRobotsTxt => write!(f, "forbidden by robots.txt"),
+            FailureKind::RobotsTxtUnavailable => write!(f, "robots.txt unavailable"),
+            FailureKind::CheckTimeBudgetExceeded => write!(f, "check time budget exceeded"),
+            FailureKind::InvalidNodeInfo => write!(f, "invalid NodeInfo"),
+            FailureKind::CrossOriginRedirect => write!(f, "cross-origin redirect"),
+            FailureKind::TemporaryRedirect => write!(f, "temporary redirect"),
+            FailureKind::InvalidRedirect => write!(f, "invalid redirect"),
+            FailureKind::NoResponse => write!(f, "no response from checker"),
+            FailureKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl FailureKind {
+    /// Returns `true` if the failure doesn't say anything about whether the instance is alive, so
+    /// it shouldn't change the instance's state.
+    pub fn is_inconclusive(&self) -> bool {
+        matches!(
+            self,
+            FailureKind::RobotsTxtUnavailable | FailureKind::CheckTimeBudgetExceeded
+        )
+    }
+}
+
 /// Messages that the checker can send to the orchestrator.
+///
+/// Each message is a single line of JSON. The checker starts with `Hello`, and ends with either
+/// `Done` or `Failed`; if the orchestrator doesn't see either of those, the checker crashed.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum CheckerResponse {
+    /// The first message of the conversation.
+    Hello { version: u32 },
+
+    /// The checker fetched a fresh copy of robots.txt, which should be cached for future checks.
+    RobotsTxt { robots_txt: RobotsTxt },
+
     /// The state of the instance.
     State { state: InstanceState },
 
+    /// The software that the instance runs. Sent after `State`, if the instance is alive.
+    Software { name: String },
+
+    /// The instance served an endpoint with these validators, which should be used in the next
+    /// check. Empty validators mean that the old ones should be forgotten.
+    Validators {
+        endpoint: Endpoint,
+        validators: Validators,
+    },
+
     /// The instance peers with another instance, which is located at `hostname`.
     Peer { peer: Host },
+
+    /// The peers list didn't change since the previous check. Sent instead of `Peer` messages.
+    PeersUnchanged,
+
+    /// The checker is still working, e.g. downloading a large peers list.
+    Heartbeat,
+
+    /// The check is finished. This is the last message.
+    Done,
+
+    /// The check failed. This is the last message.
+    Failed { kind: FailureKind },
+}
+
+/// Reads `CheckerResponse` messages from a pipe, one line at a time.
+///
+/// Unlike `BufRead::lines()`, this won't buffer more than `MAX_MESSAGE_LEN` bytes waiting for the
+/// end of line, and won't wait longer than the given timeout for the next chunk of data.
+pub struct MessageReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    timeout: Duration,
+}
+
+impl<R: Read + AsRawFd> MessageReader<R> {
+    pub fn new(inner: R, timeout: Duration) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            timeout,
+        }
+    }
+
+    /// Read the next message. Returns `None` if the other side closed the pipe.
+    pub fn next_message(&mut self) -> anyhow::Result<Option<CheckerResponse>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+                line.pop();
+                let message = serde_json::from_slice(&line)
+                    .context(with_loc!("Failed to deserialize checker's response"))?;
+                return Ok(Some(message));
+            }
+
+            if self.buffer.len() >= MAX_MESSAGE_LEN {
+                bail!("Checker's message is longer than {} bytes", MAX_MESSAGE_LEN);
+            }
+
+            wait_until_readable(self.inner.as_raw_fd(), self.timeout)
+                .context(with_loc!("Waiting for checker's response"))?;
+
+            let mut chunk = [0; 8192];
+            let read = self
+                .inner
+                .read(&mut chunk)
+                .context(with_loc!("Failed to read checker's response"))?;
+            if read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                bail!("Checker's response ended in the middle of a message");
+            }
+            let chunk = chunk
+                .get(..read)
+                .ok_or_else(|| anyhow!("Read more bytes than the buffer can hold"))?;
+            self.buffer.extend_from_slice(chunk);
+        }
+    }
+}
+
+/// Block until `fd` has data to read (or is closed), or until `timeout` elapses.
+fn wait_until_readable(fd: RawFd, timeout: Duration) -> anyhow::Result<()> {
+    let timeout_ms = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is a valid, initialized struct, and we pass a count of exactly one.
+        let result = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        match result {
+            0 => bail!("Checker sent nothing for {:?}", timeout),
+            -1 => {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error.into());
+                }
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    /// Returns a reader with `data` already waiting in it, plus the writing end of the socket.
+    fn reader_for(data: &[u8]) -> (UnixStream, MessageReader<UnixStream>) {
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        writer.write_all(data).unwrap();
+        (
+            writer,
+            MessageReader::new(reader, Duration::from_millis(100)),
+        )
+    }
+
+    #[test]
+    fn reads_messages_until_pipe_is_closed() {
+        let (writer, mut reader) =
+            reader_for(b"{\"Hello\":{\"version\":1}}\n\"Heartbeat\"\n\"Done\"\n");
+        drop(writer);
+
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(CheckerResponse::Hello { version: 1 })
+        );
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(CheckerResponse::Heartbeat)
+        );
+        assert_eq!(reader.next_message().unwrap(), Some(CheckerResponse::Done));
+        assert_eq!(reader.next_message().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_overly_long_messages() {
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        let mut reader = MessageReader::new(reader, Duration::from_millis(100));
+        // The message doesn't fit into the socket's buffer, so write it from another thread.
+        let writer = std::thread::spawn(move || {
+            let mut data = vec![b'"'; MAX_MESSAGE_LEN];
+            data.push(b'\n');
+            // This fails once the reader gives up and closes its end.
+            let _ = writer.write_all(&data);
+        });
+        assert!(reader.next_message().is_err());
+        drop(reader);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let (writer, mut reader) = reader_for(b"\"Heartbeat\"\n\"Do");
+        drop(writer);
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(CheckerResponse::Heartbeat)
+        );
+        assert!(reader.next_message().is_err());
+    }
+
+    #[test]
+    fn times_out_if_checker_is_silent() {
+        let (_writer, mut reader) = reader_for(b"\"Heartbeat\"\n\"Do");
+        assert_eq!(
+            reader.next_message().unwrap(),
+            Some(CheckerResponse::Heartbeat)
+        );
+        assert!(reader.next_message().is_err());
+    }
+
+    #[test]
+    fn proxy_routes_match_domain_suffixes() {
+        let route = |suffix: &str| ProxyRoute {
+            suffix: suffix.to_string(),
+            proxy: "socks5://127.0.0.1:9050".to_string(),
+        };
+
+        assert!(route(".onion").matches("example.onion"));
+        assert!(route("onion").matches("foo.example.onion"));
+        assert!(route(".onion").matches("EXAMPLE.ONION."));
+        assert!(!route(".onion").matches("example.com"));
+        assert!(!route(".onion").matches("examplenotonion"));
+        assert!(route(".example.com").matches("example.com"));
+        assert!(!route("ample.com").matches("example.com"));
+        assert!(route("").matches("example.com"));
+    }
 }
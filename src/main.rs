@@ -5,8 +5,7 @@
     clippy::integer_division,
     clippy::indexing_slicing,
     clippy::arithmetic_side_effects,
-    clippy::panic,
-    clippy::match_on_vec_items
+    clippy::panic
 )]
 
 use anyhow::{anyhow, bail};
@@ -14,49 +13,113 @@ use slog::{error, o, Drain, Logger};
 use url::Host;
 
 mod checker;
+mod config;
+mod control;
+mod database_stats;
 mod db;
 mod domain;
+mod export;
 mod instance_adder;
 mod ipc;
 mod logging_helpers;
+mod moderation;
 mod orchestrator;
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod simulation;
 mod time;
+mod transitions;
 
-struct Args {
-    add_instances: bool,
-    host_to_check: Option<String>,
+/// What the program was asked to do.
+enum Command {
+    /// Run the orchestrator; this is the default.
+    Orchestrate,
+
+    /// Read instances from stdin and add them to the database.
+    AddInstances,
+
+    /// Check the given host. This is how the orchestrator runs checkers.
+    Check(String),
+
+    /// Print statistics about the instances in the database.
+    Stats,
+
+    /// Print all instances in the database, with their states and uptime, as JSON.
+    Export,
+
+    /// Look at or act on the moderation queue.
+    Moderation(moderation::Action),
+
+    /// Send a request to the running orchestrator.
+    Control(control::Request),
+
+    /// Print the logged changes of instances' states.
+    Transitions(transitions::Query),
+}
+
+/// Read the value of the current option as a string.
+fn string_value(parser: &mut lexopt::Parser) -> anyhow::Result<String> {
+    // .into_string() returns Result<String, OsString> , and OsString can't be converted to
+    // anyhow::Error. To fix this, we convert the error into String.
+    parser
+        .value()?
+        .into_string()
+        .map_err(|ostr| anyhow!("{}", ostr.to_string_lossy()))
 }
 
-fn parse_args() -> anyhow::Result<Args> {
+fn parse_args() -> anyhow::Result<Command> {
     use lexopt::prelude::*;
 
-    let mut add_instances = false;
-    let mut host_to_check = None;
+    let mut commands = vec![];
     let mut parser = lexopt::Parser::from_env();
     while let Some(arg) = parser.next()? {
         match arg {
-            Long("add-instances") => add_instances = true,
-            Long("check") => {
-                let value = parser.value()?;
-                // .into_string() returns Result<String, OsString> , and OsString can't be
-                // converted to anyhow::Error. To fix this, we convert the error into String.
-                let value = value
-                    .into_string()
-                    .map_err(|ostr| anyhow!("{}", ostr.to_string_lossy()))?;
-                host_to_check = Some(value);
+            Long("add-instances") => commands.push(Command::AddInstances),
+            Long("check") => commands.push(Command::Check(string_value(&mut parser)?)),
+            Long("stats") => commands.push(Command::Stats),
+            Long("export") => commands.push(Command::Export),
+            Long("moderation-list") => commands.push(Command::Moderation(moderation::Action::List)),
+            Long("moderation-approve") => commands.push(Command::Moderation(
+                moderation::Action::Approve(string_value(&mut parser)?),
+            )),
+            Long("moderation-reject") => commands.push(Command::Moderation(
+                moderation::Action::Reject(string_value(&mut parser)?),
+            )),
+            Long("control") => {
+                let request = match string_value(&mut parser)?.as_str() {
+                    "pause" => control::Request::Pause,
+                    "resume" => control::Request::Resume,
+                    "check-now" => control::Request::CheckNow {
+                        host: string_value(&mut parser)?,
+                    },
+                    "regenerate-list" => control::Request::RegenerateList,
+                    "status" => control::Request::Status,
+                    other => bail!(
+                        "Unknown control command {}; expected pause, resume, check-now HOST, \
+                        regenerate-list, or status",
+                        other
+                    ),
+                };
+                commands.push(Command::Control(request));
             }
+            Long("transitions") => commands.push(Command::Transitions(transitions::Query::Host(
+                string_value(&mut parser)?,
+            ))),
+            Long("transitions-between") => commands.push(Command::Transitions(
+                transitions::Query::Between(string_value(&mut parser)?, string_value(&mut parser)?),
+            )),
             _ => return Err(arg.unexpected().into()),
         }
     }
 
-    if add_instances && host_to_check.is_some() {
-        bail!("--add-instances and --check are mutually exclusive");
+    if commands.len() > 1 {
+        bail!(
+            "--add-instances, --check, --stats, --export, --moderation-*, --control, and \
+            --transitions* are mutually exclusive"
+        );
     }
 
-    Ok(Args {
-        add_instances,
-        host_to_check,
-    })
+    Ok(commands.pop().unwrap_or(Command::Orchestrate))
 }
 
 fn main() -> anyhow::Result<()> {
@@ -68,16 +131,17 @@ fn main() -> anyhow::Result<()> {
 }
 
 fn logged_main(logger: Logger) -> anyhow::Result<()> {
-    let args = parse_args()?;
-    if args.add_instances {
-        instance_adder::main(logger)
-    } else {
-        match args.host_to_check {
-            None => orchestrator::main(logger),
-            Some(host) => {
-                let host = Host::parse(&host)?;
-                checker::main(logger, host)
-            }
+    match parse_args()? {
+        Command::Orchestrate => orchestrator::main(logger),
+        Command::AddInstances => instance_adder::main(logger),
+        Command::Check(host) => {
+            let host = Host::parse(&host)?;
+            checker::main(logger, host)
         }
+        Command::Stats => database_stats::main(),
+        Command::Export => export::main(),
+        Command::Moderation(action) => moderation::main(action),
+        Command::Control(request) => control::main(request),
+        Command::Transitions(query) => transitions::main(query),
     }
 }
@@ -0,0 +1,53 @@
+//! Let the administrator decide on groups of instances held for moderation.
+use crate::{
+    db::{self, ModerationDecision},
+    domain::Domain,
+};
+use anyhow::bail;
+
+/// What to do with the moderation queue.
+pub enum Action {
+    /// Print the groups that hold hosts out of the list.
+    List,
+
+    /// Allow all hosts under the given registrable domain into the list.
+    Approve(String),
+
+    /// Keep all hosts under the given registrable domain out of the list.
+    Reject(String),
+}
+
+pub fn main(action: Action) -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+
+    let (domain, decision) = match action {
+        Action::List => {
+            for group in db::get_moderation_groups(&conn)? {
+                println!(
+                    "{}: {} ({} held)",
+                    group.registrable_domain, group.decision, group.held
+                );
+            }
+            return Ok(());
+        }
+        Action::Approve(domain) => (domain, ModerationDecision::Approved),
+        Action::Reject(domain) => (domain, ModerationDecision::Rejected),
+    };
+
+    let registrable_domain = Domain::from_str(&domain)?.registrable_domain().to_owned();
+    if registrable_domain != domain.to_lowercase() {
+        bail!(
+            "{} is not a registrable domain; did you mean {}?",
+            domain,
+            registrable_domain
+        );
+    }
+    db::set_moderation_decision(&conn, &registrable_domain, decision)?;
+    println!(
+        "{} is {}; the change will show up in the next list of instances",
+        registrable_domain, decision
+    );
+
+    Ok(())
+}
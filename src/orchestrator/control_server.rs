@@ -0,0 +1,206 @@
+//! Accept commands from the administrator through a Unix socket.
+use crate::{
+    control::{self, Request, Response, Status},
+    domain::Domain,
+    orchestrator::stats::Stats,
+    with_loc,
+};
+use anyhow::Context;
+use slog::{error, info, Logger};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What the administrator asked the orchestrator to do, and what the orchestrator reports back.
+///
+/// The control socket thread fills in the requests, and the orchestrator's main loop acts on them.
+#[derive(Default)]
+pub struct Control {
+    paused: AtomicBool,
+    regenerate_list: AtomicBool,
+    check_now: Mutex<Vec<Domain>>,
+
+    queue_lag_seconds: AtomicU64,
+    last_list_generation: Mutex<Option<SystemTime>>,
+}
+
+impl Control {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` if the list should be generated now. The request is forgotten afterwards.
+    pub fn take_regenerate_list_request(&self) -> bool {
+        self.regenerate_list.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns the hosts that should be checked now. The requests are forgotten afterwards.
+    pub fn take_check_now_requests(&self) -> Vec<Domain> {
+        std::mem::take(
+            &mut *self
+                .check_now
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    pub fn record_queue_lag(&self, lag: Duration) {
+        self.queue_lag_seconds
+            .store(lag.as_secs(), Ordering::Relaxed);
+    }
+
+    pub fn queue_lag_seconds(&self) -> u64 {
+        self.queue_lag_seconds.load(Ordering::Relaxed)
+    }
+
+    pub fn record_list_generation(&self, at: SystemTime) {
+        *self
+            .last_list_generation
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(at);
+    }
+
+    fn handle(&self, stats: &Stats, request: Request) -> Response {
+        match request {
+            Request::Pause => self.paused.store(true, Ordering::Relaxed),
+            Request::Resume => self.paused.store(false, Ordering::Relaxed),
+            Request::CheckNow { host } => match Domain::from_str(&host) {
+                Ok(domain) => self
+                    .check_now
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(domain),
+                Err(e) => {
+                    return Response::Error {
+                        message: e.to_string(),
+                    }
+                }
+            },
+            Request::RegenerateList => self.regenerate_list.store(true, Ordering::Relaxed),
+            Request::Status => {
+                let last_list_generation = self
+                    .last_list_generation
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                    .map(|since_epoch| since_epoch.as_secs());
+                return Response::Status(Status {
+                    paused: self.is_paused(),
+                    running_checks: stats.running_checks(),
+                    queue_lag_seconds: self.queue_lag_seconds(),
+                    last_list_generation,
+                });
+            }
+        }
+        Response::Ok
+    }
+}
+
+/// Start a thread that serves the control socket.
+///
+/// The socket is only accessible to the user that runs the orchestrator. A socket left over from
+/// the previous run is replaced.
+pub fn spawn(logger: Logger, control: Arc<Control>, stats: Arc<Stats>) -> anyhow::Result<()> {
+    match std::fs::remove_file(control::SOCKET_FILENAME) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(with_loc!("Removing the old control socket")),
+    }
+    let listener = UnixListener::bind(control::SOCKET_FILENAME)
+        .context(with_loc!("Creating the control socket"))?;
+    std::fs::set_permissions(
+        control::SOCKET_FILENAME,
+        std::fs::Permissions::from_mode(0o600),
+    )
+    .context(with_loc!("Restricting access to the control socket"))?;
+
+    std::thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .context(with_loc!("Accepting a connection"))
+                    .and_then(|stream| serve(&logger, &control, &stats, stream));
+                if let Err(e) = result {
+                    error!(logger, "Control socket error: {:?}", e);
+                }
+            }
+        })
+        .context(with_loc!("Spawning the control socket thread"))?;
+    Ok(())
+}
+
+/// Remove the control socket, so that clients don't try to talk to an orchestrator that's gone.
+pub fn remove_socket(logger: &Logger) {
+    if let Err(e) = std::fs::remove_file(control::SOCKET_FILENAME) {
+        error!(logger, "Failed to remove the control socket: {}", e);
+    }
+}
+
+fn serve(
+    logger: &Logger,
+    control: &Control,
+    stats: &Stats,
+    stream: UnixStream,
+) -> anyhow::Result<()> {
+    stream
+        .set_read_timeout(Some(control::TIMEOUT))
+        .context(with_loc!("Setting a read timeout"))?;
+    stream
+        .set_write_timeout(Some(control::TIMEOUT))
+        .context(with_loc!("Setting a write timeout"))?;
+
+    let response = match control::read_message::<Request, _>(&stream) {
+        Ok(request) => {
+            info!(logger, "Control request: {:?}", request);
+            control.handle(stats, request)
+        }
+        Err(e) => Response::Error {
+            message: format!("{:#}", e),
+        },
+    };
+    control::write_message(&stream, &response).context(with_loc!("Sending the response"))
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_are_handed_over_to_the_main_loop() {
+        let control = Control::default();
+        let stats = Stats::default();
+
+        assert_eq!(control.handle(&stats, Request::Pause), Response::Ok);
+        assert!(control.is_paused());
+        assert_eq!(control.handle(&stats, Request::Resume), Response::Ok);
+        assert!(!control.is_paused());
+
+        assert_eq!(
+            control.handle(&stats, Request::RegenerateList),
+            Response::Ok
+        );
+        assert!(control.take_regenerate_list_request());
+        assert!(!control.take_regenerate_list_request());
+
+        let request = Request::CheckNow {
+            host: "Example.com".to_string(),
+        };
+        assert_eq!(control.handle(&stats, request), Response::Ok);
+        let request = Request::CheckNow {
+            host: "not a domain".to_string(),
+        };
+        assert!(matches!(
+            control.handle(&stats, request),
+            Response::Error { .. }
+        ));
+        assert_eq!(
+            control.take_check_now_requests(),
+            vec![Domain::from_str("example.com").unwrap()]
+        );
+        assert!(control.take_check_now_requests().is_empty());
+    }
+}
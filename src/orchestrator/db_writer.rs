@@ -0,0 +1,308 @@
+//! A thread that performs all of the Orchestrator's writes into the database.
+//!
+//! SQLite only lets one connection write at a time, so threads that write on their own connections
+//! mostly wait for each other. Instead, they send commands to the writer thread, which commits them
+//! in batches, many commands per transaction. Reads are still done on separate connections.
+use crate::{
+    db::{self, CheckRecord, FailurePolicy, StatePolicy},
+    domain::Domain,
+    ipc::{Endpoint, FailureKind, RobotsTxt, Validators},
+    time::Env,
+    with_loc,
+};
+use anyhow::{anyhow, Context};
+use rusqlite::Connection;
+use slog::{error, Logger};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How many commands can be waiting for the writer before senders start to block.
+const QUEUE_CAPACITY: usize = 1024;
+/// Maximum number of commands committed in one transaction.
+const MAX_BATCH_SIZE: usize = 256;
+/// How long the writer waits for more commands before committing the batch.
+const MAX_BATCH_DELAY: Duration = Duration::from_millis(100);
+
+/// A change to the database.
+#[derive(Debug)]
+pub enum Command {
+    /// Add instances that were found in a peers list, and note that the known ones were listed.
+    AddInstances {
+        instances: Vec<Domain>,
+        policy: StatePolicy,
+    },
+    MarkAlive {
+        instance: Domain,
+        hide_from_list: bool,
+        policy: StatePolicy,
+    },
+    MarkDead {
+        instance: Domain,
+        kind: FailureKind,
+        failure_policy: FailurePolicy,
+        state_policy: StatePolicy,
+    },
+    MarkInconclusive {
+        instance: Domain,
+        kind: FailureKind,
+    },
+    MarkMoved {
+        instance: Domain,
+        to: Domain,
+        policy: StatePolicy,
+    },
+    /// Reschedule the instances, and note that their checks are in flight.
+    StartChecks {
+        instances: Vec<Domain>,
+        policy: StatePolicy,
+    },
+    FinishCheck(Domain),
+    /// Add the check to the history, and forget the checks that are older than `retention`.
+    RecordCheck {
+        instance: Domain,
+        record: CheckRecord,
+        retention: Duration,
+    },
+    Postpone {
+        instance: Domain,
+        until: std::time::SystemTime,
+    },
+    SetRobotsTxt {
+        instance: Domain,
+        robots_txt: RobotsTxt,
+    },
+    SetSoftware {
+        instance: Domain,
+        software: String,
+    },
+    SetValidators {
+        instance: Domain,
+        endpoint: Endpoint,
+        validators: Validators,
+    },
+    UpdateModerationGroups(Vec<(String, u64)>),
+    /// Does nothing. Executing it waits until all the commands sent before it are committed.
+    Flush,
+}
+
+impl Command {
+    fn apply(&self, conn: &mut Connection) -> anyhow::Result<()> {
+        let env = Env::system();
+        match self {
+            Command::AddInstances { instances, policy } => {
+                for instance in instances {
+                    db::add_instance(conn, instance, env)?;
+                    db::note_seen_in_peers(conn, instance, policy, env)?;
+                }
+                Ok(())
+            }
+            Command::MarkAlive {
+                instance,
+                hide_from_list,
+                policy,
+            } => db::mark_alive(conn, instance, *hide_from_list, policy, env),
+            Command::MarkDead {
+                instance,
+                kind,
+                failure_policy,
+                state_policy,
+            } => db::mark_dead(conn, instance, *kind, failure_policy, state_policy, env),
+            Command::MarkInconclusive { instance, kind } => {
+                db::mark_inconclusive(conn, instance, *kind, env)
+            }
+            Command::MarkMoved {
+                instance,
+                to,
+                policy,
+            } => db::mark_moved(conn, instance, to, policy, env),
+            Command::StartChecks { instances, policy } => {
+                db::start_checks(conn, instances, policy, env)
+            }
+            Command::FinishCheck(instance) => db::finish_check(conn, instance),
+            Command::RecordCheck {
+                instance,
+                record,
+                retention,
+            } => db::record_check(conn, instance, record, *retention, env),
+            Command::Flush => Ok(()),
+            Command::Postpone { instance, until } => db::postpone(conn, instance, *until),
+            Command::SetRobotsTxt {
+                instance,
+                robots_txt,
+            } => db::set_robots_txt(conn, instance, robots_txt),
+            Command::SetSoftware { instance, software } => {
+                db::set_software(conn, instance, software)
+            }
+            Command::SetValidators {
+                instance,
+                endpoint,
+                validators,
+            } => db::set_validators(conn, instance, *endpoint, validators),
+            Command::UpdateModerationGroups(held) => db::update_moderation_groups(conn, held),
+        }
+    }
+}
+
+/// A command, plus a way to tell the sender that it was committed (if the sender wants to know).
+type Message = (Command, Option<Sender<anyhow::Result<()>>>);
+
+/// A handle for sending commands to the writer thread.
+#[derive(Clone)]
+pub struct DbWriter {
+    messages: SyncSender<Message>,
+}
+
+impl DbWriter {
+    /// Start the writer thread, which will write into the database via `conn`.
+    ///
+    /// The thread stops once all the handles are dropped and the remaining commands are committed.
+    pub fn spawn(logger: Logger, conn: Connection) -> anyhow::Result<(Self, JoinHandle<()>)> {
+        let (messages, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let thread = std::thread::Builder::new()
+            .name("db-writer".to_string())
+            .spawn(move || run(logger, conn, receiver))
+            .context(with_loc!("Spawning the database writer thread"))?;
+        Ok((Self { messages }, thread))
+    }
+
+    /// Queue the command. Errors are logged by the writer thread.
+    pub fn send(&self, command: Command) -> anyhow::Result<()> {
+        self.messages
+            .send((command, None))
+            .map_err(|_| anyhow!("The database writer thread has stopped"))
+    }
+
+    /// Wait until all the commands sent so far are committed.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.execute(Command::Flush)
+    }
+
+    /// Queue the command and wait until it's committed.
+    pub fn execute(&self, command: Command) -> anyhow::Result<()> {
+        let (reply, result) = mpsc::channel();
+        self.messages
+            .send((command, Some(reply)))
+            .map_err(|_| anyhow!("The database writer thread has stopped"))?;
+        result
+            .recv()
+            .map_err(|_| anyhow!("The database writer thread has stopped"))?
+    }
+}
+
+fn run(logger: Logger, mut conn: Connection, receiver: Receiver<Message>) {
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now().checked_add(MAX_BATCH_DELAY);
+        while batch.len() < MAX_BATCH_SIZE {
+            let timeout = deadline.map_or(Duration::ZERO, |deadline| {
+                deadline.saturating_duration_since(Instant::now())
+            });
+            match receiver.recv_timeout(timeout) {
+                Ok(message) => batch.push(message),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        match db::on_sqlite_busy_retry_indefinitely(&mut || commit(&mut conn, &batch)) {
+            Ok(results) => {
+                for ((command, reply), result) in batch.into_iter().zip(results) {
+                    if let Err(e) = &result {
+                        error!(
+                            logger,
+                            "Failed to write into the database: {:?}", e;
+                            "command" => format!("{:?}", command));
+                    }
+                    if let Some(reply) = reply {
+                        // The sender might not be waiting for the reply anymore, which is fine.
+                        let _ = reply.send(result);
+                    }
+                }
+            }
+            Err(e) => {
+                error!(
+                    logger,
+                    "Failed to commit {} commands into the database: {:?}",
+                    batch.len(),
+                    e
+                );
+                for (_, reply) in batch {
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Err(anyhow!("Failed to commit the batch: {}", e)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Apply all the commands in a single transaction.
+///
+/// A command that fails is rolled back, but doesn't take the rest of the batch down with it.
+/// Returns the result of each command.
+fn commit(conn: &mut Connection, batch: &[Message]) -> anyhow::Result<Vec<anyhow::Result<()>>> {
+    conn.execute_batch("BEGIN IMMEDIATE")
+        .context(with_loc!("Beginning a transaction"))?;
+
+    let results = batch
+        .iter()
+        .map(|(command, _)| command.apply(conn))
+        .collect();
+
+    if let Err(e) = conn.execute_batch("COMMIT") {
+        if let Err(e) = conn.execute_batch("ROLLBACK") {
+            return Err(e).context(with_loc!("Rolling back the transaction"));
+        }
+        return Err(e).context(with_loc!("Committing the transaction"));
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::db::InstanceState;
+
+    #[test]
+    fn failed_command_does_not_affect_the_rest_of_the_batch() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::open(file.path()).unwrap();
+        db::init(&mut conn).unwrap();
+
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let (writer, thread) =
+            DbWriter::spawn(logger, Connection::open(file.path()).unwrap()).unwrap();
+
+        let alive = Domain::from_str("alive.example.com").unwrap();
+        let discovered = Domain::from_str("discovered.example.com").unwrap();
+        let unknown = Domain::from_str("unknown.example.com").unwrap();
+        writer
+            .send(Command::AddInstances {
+                instances: vec![alive.clone(), discovered],
+                policy: StatePolicy::default(),
+            })
+            .unwrap();
+        writer
+            .send(Command::MarkAlive {
+                instance: alive,
+                hide_from_list: false,
+                policy: StatePolicy::default(),
+            })
+            .unwrap();
+        let result = writer.execute(Command::MarkAlive {
+            instance: unknown,
+            hide_from_list: false,
+            policy: StatePolicy::default(),
+        });
+        assert!(result.is_err());
+
+        drop(writer);
+        thread.join().unwrap();
+        assert_eq!(
+            db::count_instances_by_state(&conn).unwrap(),
+            vec![(InstanceState::Discovered, 2), (InstanceState::Alive, 1)]
+        );
+    }
+}
@@ -1,17 +1,262 @@
-use crate::{domain::Domain, ipc, orchestrator::db, with_loc};
+use crate::{
+    config::Config,
+    db::{CheckOutcome, CheckRecord, FailurePolicy, StatePolicy},
+    domain::Domain,
+    ipc,
+    orchestrator::{
+        db,
+        db_writer::{self, DbWriter},
+        politeness::{Key, Permit, Politeness},
+        stats::Stats,
+    },
+    time::{self, Env},
+    with_loc,
+};
 use anyhow::{anyhow, bail, Context};
-use rusqlite::Connection;
 use slog::{error, info, Logger};
 use std::env;
-use std::io::{BufRead, BufReader};
-use std::process::{Child, Command, Stdio};
+use std::io::Write;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Maximum size of checker's virtual memory, in bytes.
+const CHECKER_ADDRESS_SPACE_LIMIT: libc::rlim_t = 1024 * 1024 * 1024;
+/// Amount of CPU time after which the checker receives SIGXCPU, in seconds.
+const CHECKER_CPU_SECONDS_SOFT_LIMIT: libc::rlim_t = 60;
+/// Amount of CPU time after which the checker is killed by the kernel, in seconds.
+const CHECKER_CPU_SECONDS_HARD_LIMIT: libc::rlim_t = 65;
+/// Maximum number of file descriptors the checker can have open at once.
+const CHECKER_OPEN_FILES_LIMIT: libc::rlim_t = 64;
+/// If the checker doesn't send anything for this long (not even a heartbeat), it's considered hung.
+const CHECKER_SILENCE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many peers are sent to the database writer at once.
+const PEERS_PER_COMMAND: usize = 1000;
+
+/// Check the instance and record the results.
+///
+/// `_domain_permit` is held for the duration of the check. The permit for the instance's IP
+/// addresses is acquired here; if that's not possible, the check is postponed.
+pub fn run(
+    logger: Logger,
+    stats: &Stats,
+    config: &Config,
+    politeness: &Arc<Politeness>,
+    writer: &DbWriter,
+    instance: Domain,
+    _domain_permit: Permit,
+) -> anyhow::Result<()> {
+    let conn = db::open()?;
+
+    let addresses = resolve_addresses(&instance, &config.proxies);
+    let Some(_address_permit) =
+        politeness.try_acquire(addresses.into_iter().map(Key::Address).collect())
+    else {
+        info!(
+            logger,
+            "Too many checks of hosts at the same address, postponing the check"
+        );
+        stats.record_postponed_check();
+        let until = time::in_about_a_minute(Env::system())?;
+        return writer.send(db_writer::Command::Postpone { instance, until });
+    };
 
-pub fn run(logger: Logger, instance: Domain) -> anyhow::Result<()> {
-    let mut conn = db::open()?;
     println!("Checking {}", instance);
 
+    let robots_txt = db::on_sqlite_busy_retry(&mut || db::get_robots_txt(&conn, &instance))
+        .unwrap_or_else(|e| {
+            error!(logger, "Failed to get cached robots.txt: {:?}", e);
+            None
+        });
+    let ignore_unreachable_robots_txt = db::on_sqlite_busy_retry(&mut || {
+        db::is_robots_txt_long_unavailable(&conn, &instance, &config.failure_policy)
+    })
+    .unwrap_or_else(|e| {
+        error!(
+            logger,
+            "Failed to check how long robots.txt is unavailable: {:?}", e
+        );
+        false
+    });
+    let validators = db::on_sqlite_busy_retry(&mut || db::get_validators(&conn, &instance))
+        .unwrap_or_else(|e| {
+            error!(logger, "Failed to get validators: {:?}", e);
+            Default::default()
+        });
+    let software = db::on_sqlite_busy_retry(&mut || db::get_software(&conn, &instance))
+        .unwrap_or_else(|e| {
+            error!(logger, "Failed to get instance's software: {:?}", e);
+            None
+        });
+    let request = ipc::CheckerRequest {
+        robots_txt,
+        ignore_unreachable_robots_txt,
+        proxies: config.proxies.clone(),
+        allow_private_addresses: config.allow_private_addresses,
+        validators,
+        software,
+    };
+
+    let started = Instant::now();
     let mut checker = CheckerHandle::new(logger.clone(), instance.clone())?;
-    process_checker_response(&logger, &mut conn, &instance, &mut checker.inner)?;
+    let response = checker.send_request(request).and_then(|()| {
+        process_checker_response(
+            &logger,
+            stats,
+            &config.failure_policy,
+            &config.state_policy,
+            writer,
+            &instance,
+            &mut checker.inner,
+        )
+    });
+
+    // Even if the checker broke the protocol, it should still be reaped and counted
+    let exit = if response.is_ok() {
+        checker.wait()?
+    } else {
+        checker.stop()?
+    };
+    stats.record_checker_exit(&exit);
+    match exit {
+        CheckerExit::Success | CheckerExit::Failure(_) => {}
+        abnormal => error!(logger, "Checker for {} {}", instance, abnormal),
+    }
+    let (outcome, peers_count) = response?;
+    stats.record_check_outcome(outcome);
+
+    writer.send(db_writer::Command::RecordCheck {
+        instance,
+        record: CheckRecord {
+            outcome,
+            duration: started.elapsed(),
+            peers_count,
+        },
+        retention: Duration::from_secs(config.check_history_retention_seconds),
+    })
+}
+
+/// The IP addresses at which the instance is hosted.
+///
+/// This is only used to avoid checking many hosts at the same address at once, so failures are
+/// ignored: the checker will find out what's wrong. Hosts reached through a proxy are not resolved
+/// at all, since their names are only meaningful to the proxy.
+fn resolve_addresses(instance: &Domain, proxies: &[ipc::ProxyRoute]) -> Vec<IpAddr> {
+    let hostname = instance.to_string();
+    if proxies.iter().any(|route| route.matches(&hostname)) {
+        return vec![];
+    }
+    let mut addresses: Vec<IpAddr> = (hostname.as_str(), 443)
+        .to_socket_addrs()
+        .map(|addresses| addresses.map(|address| address.ip()).collect())
+        .unwrap_or_default();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// The way a checker process terminated.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheckerExit {
+    /// The checker finished the check.
+    Success,
+
+    /// The checker exited with a non-zero code, i.e. the instance didn't respond properly.
+    Failure(i32),
+
+    /// The checker ran out of CPU time and got SIGXCPU.
+    CpuLimitExceeded,
+
+    /// The checker got SIGABRT. Rust aborts the process when an allocation fails, so this most
+    /// likely means the checker hit the address space limit.
+    Aborted,
+
+    /// The checker got SIGKILL from someone other than us, most likely the OOM killer or the
+    /// kernel enforcing the hard CPU time limit.
+    Killed,
+
+    /// We killed the checker because it broke the protocol or stopped responding.
+    Stopped,
+
+    /// The checker was terminated by some other signal, e.g. SIGSEGV.
+    Crashed(i32),
+}
+
+impl CheckerExit {
+    fn from_status(status: ExitStatus) -> Self {
+        if let Some(code) = status.code() {
+            return if code == 0 {
+                Self::Success
+            } else {
+                Self::Failure(code)
+            };
+        }
+
+        match status.signal() {
+            Some(libc::SIGXCPU) => Self::CpuLimitExceeded,
+            Some(libc::SIGABRT) => Self::Aborted,
+            Some(libc::SIGKILL) => Self::Killed,
+            Some(signal) => Self::Crashed(signal),
+            // The process neither exited nor got killed by a signal. That's impossible for
+            // a process we've `wait()`ed on, so just call it a crash.
+            None => Self::Crashed(0),
+        }
+    }
+}
+
+impl std::fmt::Display for CheckerExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckerExit::Success => write!(f, "finished successfully"),
+            CheckerExit::Failure(code) => write!(f, "exited with code {}", code),
+            CheckerExit::CpuLimitExceeded => write!(f, "exceeded its CPU time limit"),
+            CheckerExit::Aborted => {
+                write!(f, "aborted (most likely exceeded its address space limit)")
+            }
+            CheckerExit::Killed => write!(f, "was killed (most likely by the OOM killer)"),
+            CheckerExit::Stopped => write!(f, "was stopped for misbehaving"),
+            CheckerExit::Crashed(signal) => write!(f, "crashed with signal {}", signal),
+        }
+    }
+}
+
+/// Put resource limits on the current process.
+///
+/// This is meant to be run in the checker process after `fork()` but before `exec()`, so it only
+/// calls async-signal-safe functions.
+fn limit_resources() -> std::io::Result<()> {
+    let limits = [
+        (
+            libc::RLIMIT_AS,
+            CHECKER_ADDRESS_SPACE_LIMIT,
+            CHECKER_ADDRESS_SPACE_LIMIT,
+        ),
+        (
+            libc::RLIMIT_CPU,
+            CHECKER_CPU_SECONDS_SOFT_LIMIT,
+            CHECKER_CPU_SECONDS_HARD_LIMIT,
+        ),
+        (
+            libc::RLIMIT_NOFILE,
+            CHECKER_OPEN_FILES_LIMIT,
+            CHECKER_OPEN_FILES_LIMIT,
+        ),
+        // Checkers handle untrusted data, so their memory shouldn't end up on disk.
+        (libc::RLIMIT_CORE, 0, 0),
+    ];
+
+    for (resource, soft, hard) in limits {
+        let limit = libc::rlimit {
+            rlim_cur: soft,
+            rlim_max: hard,
+        };
+        // SAFETY: `limit` is a valid, initialized `rlimit` struct that outlives the call.
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
 
     Ok(())
 }
@@ -26,12 +271,18 @@ impl CheckerHandle {
     fn new(logger: Logger, instance: Domain) -> anyhow::Result<Self> {
         let exe_path = env::current_exe()?;
 
-        let inner = Command::new(exe_path)
+        let mut command = Command::new(exe_path);
+        command
             .arg("--check")
             .arg(instance.to_string())
-            .stdin(Stdio::null())
+            .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::null())
+            .stderr(Stdio::null());
+        // SAFETY: `limit_resources()` only calls `setrlimit()`, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(limit_resources);
+        }
+        let inner = command
             .spawn()
             .context(with_loc!("Failed to spawn a checker"))?;
 
@@ -41,6 +292,72 @@ impl CheckerHandle {
             instance,
         })
     }
+
+    fn send_request(&mut self, request: ipc::CheckerRequest) -> anyhow::Result<()> {
+        let request = serialize_request(&self.logger, request)?;
+        // Dropping stdin closes the pipe, which tells the checker that the request is complete.
+        let mut stdin = self
+            .inner
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("Failed to connect to checker's stdin"))?;
+        stdin
+            .write_all(&request)
+            .context(with_loc!("Sending the request to the checker"))
+    }
+
+    /// Wait for the checker to exit, and find out how it went.
+    fn wait(&mut self) -> anyhow::Result<CheckerExit> {
+        let status = self
+            .inner
+            .wait()
+            .context(with_loc!("Waiting for the checker to exit"))?;
+        Ok(CheckerExit::from_status(status))
+    }
+
+    /// Kill the checker if it's still running, and find out how it went.
+    fn stop(&mut self) -> anyhow::Result<CheckerExit> {
+        if let Some(status) = self
+            .inner
+            .try_wait()
+            .context(with_loc!("Checking if the checker exited"))?
+        {
+            return Ok(CheckerExit::from_status(status));
+        }
+        self.inner
+            .kill()
+            .context(with_loc!("Killing the checker"))?;
+        self.wait()?;
+        Ok(CheckerExit::Stopped)
+    }
+}
+
+/// Serialize the request for the checker.
+///
+/// JSON escaping can make even a robots.txt that fits the size limit too large for the request. The
+/// checker would reject such a request altogether, so the cached robots.txt is dropped instead, and
+/// the checker fetches it anew.
+fn serialize_request(logger: &Logger, mut request: ipc::CheckerRequest) -> anyhow::Result<Vec<u8>> {
+    let too_long =
+        |json: &[u8]| u64::try_from(json.len()).map_or(true, |len| len > ipc::MAX_REQUEST_LEN);
+
+    let json = serde_json::to_vec(&request).context(with_loc!("Serializing checker's request"))?;
+    if !too_long(&json) || request.robots_txt.take().is_none() {
+        return Ok(json);
+    }
+
+    info!(
+        logger,
+        "Cached robots.txt is too large to be sent to the checker"
+    );
+    let json = serde_json::to_vec(&request).context(with_loc!("Serializing checker's request"))?;
+    if too_long(&json) {
+        bail!(
+            "Checker's request is longer than {} bytes",
+            ipc::MAX_REQUEST_LEN
+        );
+    }
+    Ok(json)
 }
 
 impl Drop for CheckerHandle {
@@ -72,125 +389,377 @@ impl Drop for CheckerHandle {
     }
 }
 
+/// Reads the next message that isn't a heartbeat.
+fn next_message(
+    reader: &mut ipc::MessageReader<ChildStdout>,
+) -> anyhow::Result<Option<ipc::CheckerResponse>> {
+    loop {
+        match reader.next_message()? {
+            Some(ipc::CheckerResponse::Heartbeat) => continue,
+            message => return Ok(message),
+        }
+    }
+}
+
+/// Record what the checker found out about the instance.
+///
+/// Returns how the check went, and the size of the peers list if it was fetched in full.
 fn process_checker_response(
     logger: &Logger,
-    conn: &mut Connection,
+    stats: &Stats,
+    failure_policy: &FailurePolicy,
+    state_policy: &StatePolicy,
+    writer: &DbWriter,
     target: &Domain,
     checker: &mut Child,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<(CheckOutcome, Option<u64>)> {
     let output = checker
         .stdout
         .take()
         .ok_or_else(|| anyhow!("Failed to connect to checker's stdout"))?;
-    let reader = BufReader::new(output);
-    let mut lines = reader.lines();
-
-    let state = {
-        if let Some(line) = lines.next() {
-            let line = line.context(with_loc!("Failed to read a line of checker's response"))?;
-            serde_json::from_str(&line)
-                .context(with_loc!("Failed to deserialize checker's response"))?
-        } else {
+    let mut reader = ipc::MessageReader::new(output, CHECKER_SILENCE_TIMEOUT);
+
+    match reader.next_message()? {
+        Some(ipc::CheckerResponse::Hello { version }) if version == ipc::PROTOCOL_VERSION => {}
+        Some(ipc::CheckerResponse::Hello { version }) => bail!(
+            "The checker speaks protocol version {}, but we only understand version {}",
+            version,
+            ipc::PROTOCOL_VERSION
+        ),
+        Some(_) => bail!("Expected the checker to start with Hello"),
+        None => {
             info!(
                 logger,
                 "No response from checker, marking the instance as dead"
             );
+            stats.record_incomplete_check();
 
-            return db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target));
+            writer.send(db_writer::Command::MarkDead {
+                instance: target.clone(),
+                kind: ipc::FailureKind::NoResponse,
+                failure_policy: failure_policy.clone(),
+                state_policy: *state_policy,
+            })?;
+            return Ok((CheckOutcome::Failed, None));
         }
-    };
+    }
 
-    match state {
-        ipc::CheckerResponse::Peer { peer: _ } => {
-            db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
+    let mut message = next_message(&mut reader)?;
+    if let Some(ipc::CheckerResponse::RobotsTxt { robots_txt }) = message {
+        writer.send(db_writer::Command::SetRobotsTxt {
+            instance: target.clone(),
+            robots_txt,
+        })?;
+        message = next_message(&mut reader)?;
+    }
+
+    let state = match message {
+        Some(ipc::CheckerResponse::State { state }) => state,
+        Some(ipc::CheckerResponse::Failed { kind }) if kind.is_inconclusive() => {
+            info!(
+                logger,
+                "The check was inconclusive ({}), leaving the state alone", kind
+            );
+
+            writer.send(db_writer::Command::MarkInconclusive {
+                instance: target.clone(),
+                kind,
+            })?;
+            return Ok((CheckOutcome::Inconclusive, None));
+        }
+        Some(ipc::CheckerResponse::Failed { kind }) => {
+            info!(
+                logger,
+                "The check failed ({}), marking the instance as dead", kind
+            );
+
+            writer.send(db_writer::Command::MarkDead {
+                instance: target.clone(),
+                kind,
+                failure_policy: failure_policy.clone(),
+                state_policy: *state_policy,
+            })?;
+            return Ok((CheckOutcome::Failed, None));
+        }
+        Some(ipc::CheckerResponse::Peer { peer: _ }) => {
+            writer.send(db_writer::Command::MarkDead {
+                instance: target.clone(),
+                kind: ipc::FailureKind::Other,
+                failure_policy: failure_policy.clone(),
+                state_policy: *state_policy,
+            })?;
             bail!("Expected the checker to respond with State, but it responded with Peer");
         }
-        ipc::CheckerResponse::State { state } => match state {
-            ipc::InstanceState::Alive { hide_from_list } => {
-                info!(logger, "The instance is alive");
+        Some(message) => {
+            bail!(
+                "Expected the checker to respond with State, but it responded with {:?}",
+                message
+            );
+        }
+        None => {
+            info!(
+                logger,
+                "Checker terminated before finishing the check, marking the instance as dead"
+            );
+            stats.record_incomplete_check();
 
-                db::on_sqlite_busy_retry(&mut || db::mark_alive(conn, target, hide_from_list))?;
-                process_peers(logger, conn, target, lines)?;
+            writer.send(db_writer::Command::MarkDead {
+                instance: target.clone(),
+                kind: ipc::FailureKind::NoResponse,
+                failure_policy: failure_policy.clone(),
+                state_policy: *state_policy,
+            })?;
+            return Ok((CheckOutcome::Failed, None));
+        }
+    };
+
+    let outcome = match state {
+        ipc::InstanceState::Alive { hide_from_list } => {
+            info!(logger, "The instance is alive");
+
+            writer.send(db_writer::Command::MarkAlive {
+                instance: target.clone(),
+                hide_from_list,
+                policy: *state_policy,
+            })?;
+            let peers_count =
+                process_peers(logger, stats, state_policy, writer, target, &mut reader)?;
+            return Ok((CheckOutcome::Alive, peers_count));
+        }
+        ipc::InstanceState::Moving { to } => {
+            let msg = format!(
+                "{} is moving to {}. This is a temporary redirect, so marking as dead",
+                target, to
+            );
+            info!(logger, "{}", msg);
+            println!("{}", msg);
+
+            writer.send(db_writer::Command::MarkDead {
+                instance: target.clone(),
+                kind: ipc::FailureKind::TemporaryRedirect,
+                failure_policy: failure_policy.clone(),
+                state_policy: *state_policy,
+            })?;
+            CheckOutcome::Failed
+        }
+        ipc::InstanceState::Moved { to } => match Domain::from_host(&to) {
+            Ok(to) => {
+                if &to == target {
+                    let msg = format!("{} has moved to *itself*, marking as dead", target);
+                    info!(logger, "{}", msg);
+                    println!("{}", msg);
+                    writer.send(db_writer::Command::MarkDead {
+                        instance: target.clone(),
+                        kind: ipc::FailureKind::InvalidRedirect,
+                        failure_policy: failure_policy.clone(),
+                        state_policy: *state_policy,
+                    })?;
+                    CheckOutcome::Failed
+                } else {
+                    let msg = format!("{} has moved to {}", target, to);
+                    info!(logger, "{}", msg);
+                    println!("{}", msg);
+                    writer.send(db_writer::Command::MarkMoved {
+                        instance: target.clone(),
+                        to,
+                        policy: *state_policy,
+                    })?;
+                    CheckOutcome::Moved
+                }
             }
-            ipc::InstanceState::Moving { to } => {
+
+            Err(e) => {
                 let msg = format!(
-                    "{} is moving to {}. This is a temporary redirect, so marking as dead",
-                    target, to
+                    "{} has moved to {}, which is not a valid domain name ({}); marking as dead",
+                    target, to, e
                 );
                 info!(logger, "{}", msg);
                 println!("{}", msg);
-
-                db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
-            }
-            ipc::InstanceState::Moved { to } => {
-                match Domain::from_host(&to) {
-                    Ok(to) => {
-                        if &to == target {
-                            let msg = format!("{} has moved to *itself*, marking as dead", target);
-                            info!(logger, "{}", msg);
-                            println!("{}", msg);
-                            db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
-                        } else {
-                            let msg = format!("{} has moved to {}", target, to);
-                            info!(logger, "{}", msg);
-                            println!("{}", msg);
-                            db::on_sqlite_busy_retry(&mut || db::mark_moved(conn, target, &to))?;
-                        }
-                    }
-
-                    Err(e) => {
-                        let msg = format!(
-                            "{} has moved to {}, which is not a valid domain name ({}); marking as dead",
-                            target, to, e
-                        );
-                        info!(logger, "{}", msg);
-                        println!("{}", msg);
-                        db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
-                    }
-                };
+                writer.send(db_writer::Command::MarkDead {
+                    instance: target.clone(),
+                    kind: ipc::FailureKind::InvalidRedirect,
+                    failure_policy: failure_policy.clone(),
+                    state_policy: *state_policy,
+                })?;
+                CheckOutcome::Failed
             }
         },
-    }
+    };
 
-    Ok(())
+    match next_message(&mut reader)? {
+        Some(ipc::CheckerResponse::Done) => Ok((outcome, None)),
+        Some(message) => {
+            bail!(
+                "Expected the checker to finish with Done, but it responded with {:?}",
+                message
+            )
+        }
+        None => {
+            stats.record_incomplete_check();
+            bail!("Checker terminated without finishing the check")
+        }
+    }
 }
 
+/// Add the instance's peers to the database.
+///
+/// Returns the number of peers if the whole list was fetched.
 fn process_peers(
     logger: &Logger,
-    conn: &mut Connection,
+    stats: &Stats,
+    policy: &StatePolicy,
+    writer: &DbWriter,
     target: &Domain,
-    lines: impl Iterator<Item = std::io::Result<String>>,
-) -> anyhow::Result<()> {
+    reader: &mut ipc::MessageReader<ChildStdout>,
+) -> anyhow::Result<Option<u64>> {
     let mut peers_count: Option<u64> = Some(0);
-    for response in lines {
-        let response =
-            response.context(with_loc!("Failed to read a line of checker's response"))?;
-
-        let response: ipc::CheckerResponse = serde_json::from_str(&response)
-            .context(with_loc!("Failed to deserialize checker's response"))?;
-
-        match response {
-            ipc::CheckerResponse::State { state: _ } => {
-                bail!("Expected the checker to respond with Peer, but it responded with State")
+    let mut peers_unchanged = false;
+    let mut new_peers = Vec::with_capacity(PEERS_PER_COMMAND);
+    let peers_complete = loop {
+        match next_message(reader)? {
+            Some(ipc::CheckerResponse::Software { name }) => {
+                writer.send(db_writer::Command::SetSoftware {
+                    instance: target.clone(),
+                    software: name,
+                })?;
             }
-            ipc::CheckerResponse::Peer { peer } => {
-                if let Err(e) = Domain::from_host(&peer).and_then(|peer| {
-                    db::on_sqlite_busy_retry(&mut || db::add_instance(conn, &peer))
-                }) {
-                    info!(logger, "Failed to add {} to the database: {:?}", peer, e);
-                } else {
+            Some(ipc::CheckerResponse::Validators {
+                endpoint,
+                validators,
+            }) => {
+                // The validators describe the whole peers list, so they should only be saved
+                // after the peers are.
+                flush_peers(stats, policy, writer, &mut new_peers)?;
+                writer.send(db_writer::Command::SetValidators {
+                    instance: target.clone(),
+                    endpoint,
+                    validators,
+                })?;
+            }
+            Some(ipc::CheckerResponse::PeersUnchanged) => peers_unchanged = true,
+            Some(ipc::CheckerResponse::Peer { peer }) => match Domain::from_host(&peer) {
+                Ok(peer) => {
+                    new_peers.push(peer);
+                    if new_peers.len() >= PEERS_PER_COMMAND {
+                        flush_peers(stats, policy, writer, &mut new_peers)?;
+                    }
                     peers_count = peers_count.and_then(|x| x.checked_add(1));
                 }
+                Err(e) => info!(logger, "Failed to add {} to the database: {:?}", peer, e),
+            },
+            Some(ipc::CheckerResponse::Done) => break true,
+            Some(ipc::CheckerResponse::Failed { kind }) => {
+                info!(logger, "Failed to fetch the peers list ({})", kind);
+                break false;
+            }
+            Some(message) => {
+                bail!(
+                    "Expected the checker to respond with Peer, but it responded with {:?}",
+                    message
+                )
+            }
+            None => {
+                stats.record_incomplete_check();
+                error!(
+                    logger,
+                    "Checker terminated in the middle of the peers list for {}", target
+                );
+                break false;
             }
         }
-    }
+    };
+    flush_peers(stats, policy, writer, &mut new_peers)?;
 
     let msg = match peers_count {
+        _ if peers_unchanged => format!("{}'s peers list didn't change", target),
         None => format!("{} has more than {} peers", target, u64::MAX),
+        Some(count) if !peers_complete => {
+            format!("{} has at least {} peers", target, count)
+        }
         Some(count) => format!("{} has {} peers", target, count),
     };
     info!(logger, "{}", msg);
     println!("{}", msg);
 
-    Ok(())
+    Ok(peers_count.filter(|_| peers_complete && !peers_unchanged))
+}
+
+/// Send the peers that were collected so far to the database writer.
+fn flush_peers(
+    stats: &Stats,
+    policy: &StatePolicy,
+    writer: &DbWriter,
+    peers: &mut Vec<Domain>,
+) -> anyhow::Result<()> {
+    if peers.is_empty() {
+        return Ok(());
+    }
+    stats.record_ingested_peers(peers.len());
+    writer.send(db_writer::Command::AddInstances {
+        instances: std::mem::take(peers),
+        policy: *policy,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drops_cached_robots_txt_that_does_not_fit_the_request() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let robots_txt = |body: String| ipc::RobotsTxt {
+            body,
+            status: 200,
+            fetched_at: std::time::UNIX_EPOCH,
+        };
+
+        let request = ipc::CheckerRequest {
+            robots_txt: Some(robots_txt("User-agent: *\nDisallow: /private".to_string())),
+            ..Default::default()
+        };
+        let json = serialize_request(&logger, request).unwrap();
+        let request: ipc::CheckerRequest = serde_json::from_slice(&json).unwrap();
+        assert!(request.robots_txt.is_some());
+
+        // Every control character takes six bytes once escaped
+        let request = ipc::CheckerRequest {
+            robots_txt: Some(robots_txt("\u{1}".repeat(500 * 1024))),
+            ..Default::default()
+        };
+        let json = serialize_request(&logger, request).unwrap();
+        assert!(json.len() as u64 <= ipc::MAX_REQUEST_LEN);
+        let request: ipc::CheckerRequest = serde_json::from_slice(&json).unwrap();
+        assert_eq!(request.robots_txt, None);
+    }
+
+    #[test]
+    fn classifies_checker_exits() {
+        // Raw wait statuses: exit code lives in the second byte, signal number in the first.
+        assert_eq!(
+            CheckerExit::from_status(ExitStatus::from_raw(0)),
+            CheckerExit::Success
+        );
+        assert_eq!(
+            CheckerExit::from_status(ExitStatus::from_raw(1 << 8)),
+            CheckerExit::Failure(1)
+        );
+        assert_eq!(
+            CheckerExit::from_status(ExitStatus::from_raw(libc::SIGXCPU)),
+            CheckerExit::CpuLimitExceeded
+        );
+        assert_eq!(
+            CheckerExit::from_status(ExitStatus::from_raw(libc::SIGABRT)),
+            CheckerExit::Aborted
+        );
+        assert_eq!(
+            CheckerExit::from_status(ExitStatus::from_raw(libc::SIGKILL)),
+            CheckerExit::Killed
+        );
+        assert_eq!(
+            CheckerExit::from_status(ExitStatus::from_raw(libc::SIGSEGV)),
+            CheckerExit::Crashed(libc::SIGSEGV)
+        );
+    }
 }
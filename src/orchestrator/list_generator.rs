@@ -1,11 +1,20 @@
 //! Produce a JSON list of alive instances.
-use crate::{db, with_loc};
+use crate::{
+    config::Config,
+    db::{self, ModerationDecision},
+    domain::Domain,
+    orchestrator::db_writer::{self, DbWriter},
+    with_loc,
+};
 use anyhow::Context;
-use slog::{info, Logger};
+use slog::{error, info, Logger};
+use std::collections::HashMap;
 use std::io::Write;
 
 /// Writes a JSON array of alive instances into _instances.json_.
-pub fn generate(logger: Logger) -> anyhow::Result<()> {
+///
+/// Onion services are only included if the config says so. Hosts held for moderation are left out.
+pub fn generate(logger: Logger, config: &Config, writer: &DbWriter) -> anyhow::Result<()> {
     info!(logger, "Generating a list of instances");
 
     let mut instances: Vec<String> = vec![];
@@ -13,7 +22,7 @@ pub fn generate(logger: Logger) -> anyhow::Result<()> {
     let conn = db::open()?;
     let mut statement = conn
         .prepare(
-            "SELECT hostname
+            "SELECT instances.id, hostname
             FROM instances
                 JOIN hidden_instances ON instances.id = hidden_instances.instance
             WHERE state = 1
@@ -21,7 +30,7 @@ pub fn generate(logger: Logger) -> anyhow::Result<()> {
 
             UNION
 
-            SELECT hostname
+            SELECT instances.id, hostname
             FROM instances
                 JOIN dying_state_data ON instances.id = dying_state_data.instance
                 JOIN hidden_instances ON instances.id = hidden_instances.instance
@@ -31,7 +40,7 @@ pub fn generate(logger: Logger) -> anyhow::Result<()> {
 
             UNION
 
-            SELECT instances.hostname
+            SELECT instances.id, instances.hostname
             FROM instances
                 JOIN moving_state_data ON instances.id = moving_state_data.instance
                 JOIN hidden_instances ON instances.id = hidden_instances.instance
@@ -39,14 +48,30 @@ pub fn generate(logger: Logger) -> anyhow::Result<()> {
             WHERE instances.state = 4
                 AND previous_state = 1
                 AND moved_to_instance.state != 1
-                AND hide_from_list = 0",
+                AND hide_from_list = 0
+
+            ORDER BY 1",
         )
         .context(with_loc!("Preparing a SELECT"))?;
     let mut ids = statement.query([])?;
     while let Some(row) = ids.next()? {
-        let hostname: String = row.get(0).context(with_loc!("Getting `hostname`"))?;
+        let hostname: String = row.get(1).context(with_loc!("Getting `hostname`"))?;
+        if !config.list_onion_instances && hostname.ends_with(".onion") {
+            continue;
+        }
         instances.push(hostname);
     }
+    drop(ids);
+    drop(statement);
+
+    let decisions = db::get_moderation_groups(&conn)?
+        .into_iter()
+        .map(|group| (group.registrable_domain, group.decision))
+        .collect();
+    let (instances, held) = moderate(instances, &decisions, config.moderation_threshold);
+    if let Err(e) = writer.send(db_writer::Command::UpdateModerationGroups(held)) {
+        error!(logger, "Failed to update moderation groups: {:?}", e);
+    }
 
     let instances = serde_json::to_string(&instances)
         .context(with_loc!("Serializing instances list into JSON"))?;
@@ -66,9 +91,60 @@ pub fn generate(logger: Logger) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn write(filename: &str, data: &[u8]) -> anyhow::Result<()> {
-    let mut file = tempfile::NamedTempFile::new_in(".")
-        .context(with_loc!("Creating a temporary file in current directory"))?;
+/// Hold back the hosts that need a moderator's approval to be listed.
+///
+/// `instances` should be sorted from the oldest to the newest. If more than `threshold` of them
+/// share a registrable domain, only the oldest `threshold` hosts are listed until the group is
+/// approved; if the group is rejected, none of them are.
+///
+/// Returns the hosts that can be listed, and the number of held hosts for each group that holds
+/// back any.
+fn moderate(
+    instances: Vec<String>,
+    decisions: &HashMap<String, ModerationDecision>,
+    threshold: usize,
+) -> (Vec<String>, Vec<(String, u64)>) {
+    let mut group_sizes: HashMap<String, usize> = HashMap::new();
+    let mut held: HashMap<String, u64> = HashMap::new();
+    let mut listed = Vec::with_capacity(instances.len());
+
+    for hostname in instances {
+        let registrable_domain = match Domain::from_str(&hostname) {
+            Ok(domain) => domain.registrable_domain().to_owned(),
+            Err(_) => hostname.clone(),
+        };
+        let size = group_sizes.entry(registrable_domain.clone()).or_default();
+        *size = size.saturating_add(1);
+        let is_listed = match decisions.get(&registrable_domain) {
+            Some(ModerationDecision::Approved) => true,
+            Some(ModerationDecision::Rejected) => false,
+            Some(ModerationDecision::Pending) | None => *size <= threshold,
+        };
+        if is_listed {
+            listed.push(hostname);
+        } else {
+            let count = held.entry(registrable_domain).or_default();
+            *count = count.saturating_add(1);
+        }
+    }
+
+    let mut held: Vec<(String, u64)> = held.into_iter().collect();
+    held.sort();
+    (listed, held)
+}
+
+/// Atomically replace the contents of `filename` with `data`.
+///
+/// The temporary file is created next to the destination, so that renaming it never has to cross
+/// file systems.
+pub fn write(filename: &str, data: &[u8]) -> anyhow::Result<()> {
+    let directory = match std::path::Path::new(filename).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(directory).context(with_loc!(
+        "Creating a temporary file next to the destination"
+    ))?;
     file.write_all(data)
         .context(with_loc!("Writing data into a temporary file"))?;
 
@@ -89,3 +165,52 @@ fn write(filename: &str, data: &[u8]) -> anyhow::Result<()> {
         .context(with_loc!("Renaming temporary file to the desired filename"))?;
     Ok(())
 }
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn hosts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn holds_back_newest_hosts_of_large_groups() {
+        let instances = hosts(&[
+            "a.example.com",
+            "example.org",
+            "b.example.com",
+            "c.example.com",
+            "d.example.com",
+        ]);
+        let (listed, held) = moderate(instances, &HashMap::new(), 2);
+        assert_eq!(
+            listed,
+            hosts(&["a.example.com", "example.org", "b.example.com"])
+        );
+        assert_eq!(held, vec![("example.com".to_string(), 2)]);
+    }
+
+    #[test]
+    fn follows_moderation_decisions() {
+        let instances = hosts(&[
+            "a.example.com",
+            "b.example.com",
+            "c.example.com",
+            "a.example.net",
+            "b.example.net",
+            "c.example.net",
+        ]);
+        let decisions = HashMap::from([
+            ("example.com".to_string(), ModerationDecision::Approved),
+            ("example.net".to_string(), ModerationDecision::Rejected),
+        ]);
+        let (listed, held) = moderate(instances, &decisions, 2);
+        assert_eq!(
+            listed,
+            hosts(&["a.example.com", "b.example.com", "c.example.com"])
+        );
+        assert_eq!(held, vec![("example.net".to_string(), 3)]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Export metrics for Prometheus through node_exporter's textfile collector.
+use crate::{
+    db::{self, InstanceState},
+    orchestrator::{control_server::Control, list_generator, stats::Stats},
+    with_loc,
+};
+use anyhow::Context;
+use rusqlite::Connection;
+use slog::{error, Logger};
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Prefix of all metric names.
+const PREFIX: &str = "minoru_fediverse_crawler_";
+
+/// How often the metrics file is rewritten. node_exporter reads it whenever it's scraped, so
+/// there's no point in writing it much more often than Prometheus scrapes.
+const INTERVAL: Duration = Duration::from_secs(15);
+
+/// Metrics in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    /// Start a new metric. Its samples should follow right away.
+    pub fn describe(&mut self, name: &str, kind: &str, help: &str) {
+        self.text.push_str(&format!(
+            "# HELP {}{} {}\n# TYPE {}{} {}\n",
+            PREFIX, name, help, PREFIX, name, kind
+        ));
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        if labels.is_empty() {
+            self.text
+                .push_str(&format!("{}{} {}\n", PREFIX, name, value));
+        } else {
+            self.text
+                .push_str(&format!("{}{}{{{}}} {}\n", PREFIX, name, labels, value));
+        }
+    }
+}
+
+/// Start a thread that periodically writes the metrics into `filename`.
+pub fn spawn(
+    logger: Logger,
+    filename: String,
+    stats: Arc<Stats>,
+    control: Arc<Control>,
+) -> anyhow::Result<()> {
+    let conn = db::open()?;
+    std::thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || loop {
+            if let Err(e) = write(&conn, &filename, &stats, &control) {
+                error!(logger, "Failed to write metrics: {:?}", e);
+            }
+            std::thread::sleep(INTERVAL);
+        })
+        .context(with_loc!("Spawning the metrics thread"))?;
+    Ok(())
+}
+
+fn write(
+    conn: &Connection,
+    filename: &str,
+    stats: &Stats,
+    control: &Control,
+) -> anyhow::Result<()> {
+    let instances =
+        db::count_instances_by_state(conn).context(with_loc!("Counting instances by state"))?;
+    let metrics = render(stats, control, &instances);
+    list_generator::write(filename, metrics.as_bytes())
+        .with_context(|| format!("Writing metrics into {}", filename))
+}
+
+fn render(stats: &Stats, control: &Control, instances: &[(InstanceState, u64)]) -> String {
+    let mut metrics = Metrics::default();
+    stats.write_metrics(&mut metrics);
+
+    metrics.describe(
+        "queue_lag_seconds",
+        "gauge",
+        "How late the most recently started check was, compared to its scheduled time.",
+    );
+    metrics.sample("queue_lag_seconds", &[], control.queue_lag_seconds());
+
+    metrics.describe("instances", "gauge", "Instances in the database, by state.");
+    for state in [
+        InstanceState::Discovered,
+        InstanceState::Alive,
+        InstanceState::Dying,
+        InstanceState::Dead,
+        InstanceState::Moving,
+        InstanceState::Moved,
+    ] {
+        let count = instances
+            .iter()
+            .find(|(s, _)| *s == state)
+            .map_or(0, |(_, count)| *count);
+        metrics.sample("instances", &[("state", &state.to_string())], count);
+    }
+
+    metrics.text
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::db::CheckOutcome;
+
+    #[test]
+    fn renders_prometheus_text_format() {
+        let stats = Stats::default();
+        stats.record_check_start();
+        stats.record_ingested_peers(42);
+        stats.record_check_outcome(CheckOutcome::Moved);
+        let control = Control::default();
+        control.record_queue_lag(Duration::from_secs(7));
+
+        let text = render(&stats, &control, &[(InstanceState::Alive, 3)]);
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"# TYPE minoru_fediverse_crawler_checks_started_total counter"));
+        assert!(lines.contains(&"minoru_fediverse_crawler_checks_started_total 1"));
+        assert!(lines.contains(&"minoru_fediverse_crawler_running_checks 1"));
+        assert!(lines.contains(&"minoru_fediverse_crawler_ingested_peers_total 42"));
+        assert!(
+            lines.contains(&"minoru_fediverse_crawler_checks_finished_total{outcome=\"moved\"} 1")
+        );
+        assert!(
+            lines.contains(&"minoru_fediverse_crawler_checks_finished_total{outcome=\"alive\"} 0")
+        );
+        assert!(lines.contains(&"minoru_fediverse_crawler_checker_exits_total{exit=\"crashed\"} 0"));
+        assert!(lines.contains(&"minoru_fediverse_crawler_queue_lag_seconds 7"));
+        assert!(lines.contains(&"minoru_fediverse_crawler_instances{state=\"alive\"} 3"));
+        assert!(lines.contains(&"minoru_fediverse_crawler_instances{state=\"dead\"} 0"));
+    }
+}
@@ -1,14 +1,35 @@
-use crate::{db, with_loc};
+use crate::{
+    config, db,
+    orchestrator::{
+        control_server::Control,
+        db_writer::DbWriter,
+        politeness::{Key, Politeness},
+        rate_limiter::RateLimiter,
+        scheduler::Scheduler,
+        stats::Stats,
+        systemd::Notifier,
+    },
+    time::Env,
+    with_loc,
+};
 use anyhow::Context;
-use slog::{error, o, Logger};
+use slog::{error, info, o, Logger};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
+mod control_server;
+mod db_writer;
 mod instance_checker;
 mod list_generator;
+mod metrics;
+mod politeness;
+mod rate_limiter;
+mod scheduler;
+mod stats;
+mod systemd;
 
 /// This has to be a large-ish number, so Orchestrator can out-starve any other thread
 const SQLITE_BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
@@ -21,32 +42,112 @@ const CONSTANT_WORKERS: usize = 1;
 const MAX_WORKERS: usize = 128;
 /// How long a worker will wait for work before shutting down its thread.
 const MAX_WORKER_IDLE_TIME: std::time::Duration = std::time::Duration::from_secs(3);
+/// How long to wait for the running checks to finish when shutting down. systemd kills us after
+/// 5 seconds; checks that don't make it in time are re-queued at the next start.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(4);
+/// How often the main loop checks for a shutdown request while it waits for the next check.
+const TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 pub fn main(logger: Logger) -> anyhow::Result<()> {
+    let mut notifier = Notifier::from_env(logger.clone())?;
+    let mut config = Arc::new(config::load()?);
+
     let mut conn = db::open()?;
     conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
     db::init(&mut conn)?;
-    db::reschedule_missed_checks(&mut conn)?;
+    db::reschedule_missed_checks(&mut conn, Env::system())?;
+    let requeued = db::requeue_in_flight_checks(&mut conn, Env::system())?;
+    if requeued > 0 {
+        info!(
+            logger,
+            "Re-queued {} checks that were in flight when the crawler stopped", requeued
+        );
+    }
+
+    let writer_conn = db::open()?;
+    writer_conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
+    let (writer, writer_thread) = DbWriter::spawn(logger.clone(), writer_conn)?;
 
     let pool = rusty_pool::ThreadPool::new(CONSTANT_WORKERS, MAX_WORKERS, MAX_WORKER_IDLE_TIME);
+    let stats = Arc::new(Stats::default());
+    let politeness = Arc::new(Politeness::new(config.politeness.clone()));
+    let mut scheduler = Scheduler::new();
+    let mut rate_limiter = RateLimiter::new(&config.rate_limit, Instant::now());
+    info!(
+        logger, "Limiting the rate of checks";
+        "checks_per_minute" => config.rate_limit.checks_per_minute,
+        "burst" => config.rate_limit.burst);
+
+    let control = Arc::new(Control::default());
+    control_server::spawn(logger.clone(), control.clone(), stats.clone())?;
+    if let Some(filename) = &config.metrics_textfile {
+        info!(logger, "Writing metrics into {}", filename);
+        metrics::spawn(
+            logger.clone(),
+            filename.clone(),
+            stats.clone(),
+            control.clone(),
+        )?;
+    }
 
     let terminate = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, terminate.clone())
         .context(with_loc!("Setting up a SIGINT hook"))?;
     signal_hook::flag::register(signal_hook::consts::SIGTERM, terminate.clone())
         .context(with_loc!("Setting up a SIGTERM hook"))?;
+    let reload_config = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_config.clone())
+        .context(with_loc!("Setting up a SIGHUP hook"))?;
+    let regenerate_list = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, regenerate_list.clone())
+        .context(with_loc!("Setting up a SIGUSR1 hook"))?;
+
+    notifier.ready("Started");
 
     let mut time_to_generate_a_list = SystemTime::now();
 
     let mut iteration = || -> anyhow::Result<()> {
-        if time_to_generate_a_list < SystemTime::now() {
+        if reload_config.swap(false, Ordering::Relaxed) {
+            // Running checks hold on to the old config until they finish.
+            match config::load() {
+                Ok(new_config) => {
+                    info!(logger, "Reloaded the configuration file");
+                    if new_config.metrics_textfile != config.metrics_textfile {
+                        info!(
+                            logger,
+                            "The new `metrics_textfile` will only be used after a restart"
+                        );
+                    }
+                    politeness.set_policy(new_config.politeness.clone());
+                    rate_limiter.set_limit(&new_config.rate_limit);
+                    config = Arc::new(new_config);
+                }
+                Err(e) => error!(logger, "Keeping the old configuration: {:?}", e),
+            }
+        }
+
+        if time_to_generate_a_list < SystemTime::now()
+            || control.take_regenerate_list_request()
+            || regenerate_list.swap(false, Ordering::Relaxed)
+        {
+            stats.log(&logger);
+
             let logger = logger.new(o!("list_generation" => "true"));
+            let config = config.clone();
+            let writer = writer.clone();
+            let control = control.clone();
+            let stats = stats.clone();
             pool.execute(move || {
                 let task = {
                     let logger = logger.clone();
                     move || {
-                        if let Err(e) = list_generator::generate(logger.clone()) {
-                            error!(logger, "List generator error: {:?}", e);
+                        let started_at = Instant::now();
+                        match list_generator::generate(logger.clone(), &config, &writer) {
+                            Ok(()) => {
+                                stats.record_list_generation(started_at.elapsed());
+                                control.record_list_generation(SystemTime::now());
+                            }
+                            Err(e) => error!(logger, "List generator error: {:?}", e),
                         }
                     }
                 };
@@ -56,34 +157,99 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
                 }
             });
 
-            time_to_generate_a_list = crate::time::in_about_six_hours()?;
+            time_to_generate_a_list = crate::time::in_about_six_hours(Env::system())?;
+        }
+
+        for instance in control.take_check_now_requests() {
+            info!(logger, "Checking {} as soon as possible", instance);
+            writer
+                .send(db_writer::Command::AddInstances {
+                    instances: vec![instance.clone()],
+                    policy: config.state_policy,
+                })
+                .context(with_loc!("Orchestrator adding an instance to check now"))?;
+            writer
+                .send(db_writer::Command::Postpone {
+                    instance,
+                    until: SystemTime::now(),
+                })
+                .context(with_loc!(
+                    "Orchestrator scheduling an instance to check now"
+                ))?;
+        }
+
+        if control.is_paused() {
+            std::thread::sleep(Duration::from_secs(1));
+            return Ok(());
         }
 
-        let (instance, check_time) = db::pick_next_instance(&conn)
-            .context(with_loc!("Orchestrator picking next instance"))?;
+        let Some((instance, check_time)) = scheduler
+            .next(&conn, &writer, &config.state_policy, SystemTime::now())
+            .context(with_loc!("Orchestrator picking next instance"))?
+        else {
+            std::thread::sleep(std::time::Duration::from_secs(3));
+            return Ok(());
+        };
+        // If `check_time` has already passed, do the check right away. The rate limiter below
+        // ensures that the crawler doesn't fire off many checks at once.
         let wait = check_time
             .duration_since(SystemTime::now())
-            // If `check_time` has already passed, wait a bit and do the check. The small wait is
-            // there to ensure that the crawler doesn't fire off many checks at once, potentially
-            // overloading hosted offerings like mas.to.
-            .unwrap_or(Duration::from_millis(100));
-        let three_seconds = Duration::from_secs(3);
-        if wait > three_seconds {
-            std::thread::sleep(std::time::Duration::from_secs(3));
+            .unwrap_or(Duration::ZERO);
+        // If we're shutting down, the check stays in flight and is re-queued at the next start.
+        if !sleep_unless_terminated(wait, &terminate) {
             return Ok(());
         }
-        if wait > Duration::from_secs(0) {
-            std::thread::sleep(wait);
+
+        let domain = Key::Domain(instance.registrable_domain().to_owned());
+        let Some(domain_permit) = politeness.try_acquire(vec![domain]) else {
+            stats.record_postponed_check();
+            let until = crate::time::in_about_a_minute(Env::system())?;
+            writer
+                .send(db_writer::Command::Postpone {
+                    instance: instance.clone(),
+                    until,
+                })
+                .context(with_loc!("Orchestrator postponing an instance"))?;
+            return writer
+                .send(db_writer::Command::FinishCheck(instance))
+                .context(with_loc!("Orchestrator finishing a postponed check"));
+        };
+
+        let delay = rate_limiter.acquire(Instant::now());
+        if delay > Duration::ZERO {
+            stats.record_rate_limit_delay(delay);
+            if !sleep_unless_terminated(delay, &terminate) {
+                return Ok(());
+            }
         }
-        db::reschedule(&mut conn, &instance)
-            .context(with_loc!("Orchestrator rescheduling an instance"))?;
+        control.record_queue_lag(
+            SystemTime::now()
+                .duration_since(check_time)
+                .unwrap_or(Duration::ZERO),
+        );
 
         let logger = logger.new(o!("host" => instance.to_string()));
+        let stats = stats.clone();
+        let config = config.clone();
+        let politeness = politeness.clone();
+        let writer = writer.clone();
+        stats.record_check_start();
         pool.execute(move || {
             let task = {
                 let logger = logger.clone();
+                let stats = stats.clone();
+                let writer = writer.clone();
+                let instance = instance.clone();
                 move || {
-                    if let Err(e) = instance_checker::run(logger.clone(), instance) {
+                    if let Err(e) = instance_checker::run(
+                        logger.clone(),
+                        &stats,
+                        &config,
+                        &politeness,
+                        &writer,
+                        instance,
+                        domain_permit,
+                    ) {
                         error!(logger, "Checker error: {:?}", e);
                     }
                 }
@@ -92,6 +258,10 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
             if let Err(e) = std::panic::catch_unwind(task) {
                 error!(logger, "Checker panicked: {:?}", e);
             }
+            if let Err(e) = writer.send(db_writer::Command::FinishCheck(instance)) {
+                error!(logger, "Failed to finish the check: {:?}", e);
+            }
+            stats.record_check_end();
         });
 
         Ok(())
@@ -99,12 +269,80 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
 
     loop {
         db::on_sqlite_busy_retry_indefinitely(&mut iteration)?;
+        notifier.ping(Instant::now(), || {
+            let paused = if control.is_paused() { ", paused" } else { "" };
+            format!(
+                "{} checks running, queue lag {} seconds{}",
+                stats.running_checks(),
+                control.queue_lag_seconds(),
+                paused
+            )
+        });
         if terminate.load(Ordering::Relaxed) {
             println!("Shutting down gracefully...");
             break;
         }
     }
 
-    pool.join();
+    notifier.stopping();
+    control_server::remove_socket(&logger);
+    pool.join_timeout(DRAIN_TIMEOUT);
+    let unfinished = stats.running_checks();
+    if unfinished > 0 {
+        // The checks are still recorded as in flight, so they'll be re-queued at the next start.
+        info!(
+            logger,
+            "{} checks didn't finish in time, leaving them for the next start", unfinished
+        );
+        return writer
+            .flush()
+            .context(with_loc!("Committing the results of finished checks"));
+    }
+
+    // Once the last handle is gone, the writer commits whatever is left and stops.
+    drop(writer);
+    if writer_thread.join().is_err() {
+        error!(logger, "Database writer thread panicked");
+    }
+
     Ok(())
 }
+
+/// Sleep for `duration`, or until `terminate` is set, whichever comes first.
+///
+/// Returns `false` if the sleep was cut short.
+fn sleep_unless_terminated(duration: Duration, terminate: &AtomicBool) -> bool {
+    let started = Instant::now();
+    loop {
+        if terminate.load(Ordering::Relaxed) {
+            return false;
+        }
+        let left = duration.saturating_sub(started.elapsed());
+        if left.is_zero() {
+            return true;
+        }
+        std::thread::sleep(left.min(TERMINATE_POLL_INTERVAL));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sleep_is_cut_short_by_terminate() {
+        let terminate = AtomicBool::new(false);
+        assert!(sleep_unless_terminated(
+            Duration::from_millis(10),
+            &terminate
+        ));
+
+        terminate.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        assert!(!sleep_unless_terminated(
+            Duration::from_secs(60),
+            &terminate
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
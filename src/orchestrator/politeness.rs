@@ -0,0 +1,183 @@
+//! Limits on checks of hosts that probably share hardware.
+//!
+//! A single hosting platform can run thousands of instances on subdomains of one domain, or behind
+//! a handful of IP addresses. Each check is cheap, but checking many of those hosts at once adds up
+//! to a burst of load on the same servers. To avoid that, every check holds a [`Permit`] for the
+//! registrable domain and the IP addresses of the host it checks.
+use crate::config::PolitenessPolicy;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Something that several hosts can share.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// A registrable domain, e.g. "example.com" for "foo.example.com".
+    Domain(String),
+
+    /// An IP address.
+    Address(IpAddr),
+}
+
+#[derive(Default)]
+struct Slot {
+    /// Number of checks that hold this key right now.
+    in_flight: usize,
+
+    /// When the last check that holds this key started.
+    last_start: Option<Instant>,
+}
+
+pub struct Politeness {
+    policy: Mutex<PolitenessPolicy>,
+    slots: Mutex<HashMap<Key, Slot>>,
+}
+
+impl Politeness {
+    pub fn new(policy: PolitenessPolicy) -> Self {
+        Self {
+            policy: Mutex::new(policy),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the limits. Checks that are already running keep their permits.
+    pub fn set_policy(&self, policy: PolitenessPolicy) {
+        *self.policy.lock().unwrap_or_else(PoisonError::into_inner) = policy;
+    }
+
+    /// Start a check that touches all of the `keys`. Returns `None` if that would exceed the limits
+    /// for any of them; otherwise, the keys are held until the returned permit is dropped.
+    pub fn try_acquire(self: &Arc<Self>, keys: Vec<Key>) -> Option<Permit> {
+        let policy = self
+            .policy
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let min_interval = Duration::from_secs(policy.min_seconds_between_checks);
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Forget the keys that no longer limit anything, so the map doesn't grow forever.
+        slots.retain(|_, slot| {
+            slot.in_flight > 0 || slot.last_start.is_some_and(|t| t.elapsed() < min_interval)
+        });
+
+        let is_allowed = keys.iter().all(|key| {
+            let Some(slot) = slots.get(key) else {
+                return true;
+            };
+            let limit = match key {
+                Key::Domain(_) => policy.max_checks_per_domain,
+                Key::Address(_) => policy.max_checks_per_address,
+            };
+            let recently_started = slot.last_start.is_some_and(|t| t.elapsed() < min_interval);
+            slot.in_flight < limit && !recently_started
+        });
+        if !is_allowed {
+            return None;
+        }
+
+        let now = Instant::now();
+        for key in &keys {
+            let slot = slots.entry(key.clone()).or_default();
+            slot.in_flight = slot.in_flight.saturating_add(1);
+            slot.last_start = Some(now);
+        }
+
+        Some(Permit {
+            politeness: Arc::clone(self),
+            keys,
+        })
+    }
+
+    fn release(&self, keys: &[Key]) {
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        for key in keys {
+            if let Some(slot) = slots.get_mut(key) {
+                slot.in_flight = slot.in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Proof that a check may proceed. The keys are released when this is dropped.
+pub struct Permit {
+    politeness: Arc<Politeness>,
+    keys: Vec<Key>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.politeness.release(&self.keys);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn politeness(min_seconds_between_checks: u64) -> Arc<Politeness> {
+        Arc::new(Politeness::new(PolitenessPolicy {
+            max_checks_per_domain: 2,
+            max_checks_per_address: 1,
+            min_seconds_between_checks,
+        }))
+    }
+
+    fn domain(name: &str) -> Key {
+        Key::Domain(name.to_owned())
+    }
+
+    fn address(address: &str) -> Key {
+        Key::Address(address.parse().unwrap())
+    }
+
+    #[test]
+    fn limits_concurrent_checks_per_key() {
+        let politeness = politeness(0);
+
+        let first = politeness.try_acquire(vec![domain("example.com")]);
+        let second = politeness.try_acquire(vec![domain("example.com")]);
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(politeness
+            .try_acquire(vec![domain("example.com")])
+            .is_none());
+        assert!(politeness
+            .try_acquire(vec![domain("example.org")])
+            .is_some());
+
+        drop(first);
+        assert!(politeness
+            .try_acquire(vec![domain("example.com")])
+            .is_some());
+    }
+
+    #[test]
+    fn all_keys_must_be_free() {
+        let politeness = politeness(0);
+
+        let _held = politeness.try_acquire(vec![address("192.0.2.1")]);
+        assert!(politeness
+            .try_acquire(vec![address("192.0.2.2"), address("192.0.2.1")])
+            .is_none());
+
+        // The failed attempt didn't hold on to the other address.
+        assert!(politeness.try_acquire(vec![address("192.0.2.2")]).is_some());
+    }
+
+    #[test]
+    fn spaces_out_checks_per_key() {
+        let politeness = politeness(60);
+
+        drop(politeness.try_acquire(vec![domain("example.com")]));
+        assert!(politeness
+            .try_acquire(vec![domain("example.com")])
+            .is_none());
+        assert!(politeness
+            .try_acquire(vec![domain("example.org")])
+            .is_some());
+    }
+}
@@ -0,0 +1,132 @@
+//! Cap the rate at which the orchestrator starts checks.
+use crate::config::RateLimit;
+use std::time::{Duration, Instant};
+
+/// A token bucket: it holds up to `burst` checks, and refills at the configured rate.
+///
+/// Instead of counting tokens, the bucket keeps the time it would take to earn them, so there's no
+/// rounding when the rate doesn't divide a second evenly.
+pub struct RateLimiter {
+    /// The time it takes to earn one check.
+    interval: Duration,
+
+    /// The most time that can be saved up, i.e. the time it takes to earn a full bucket.
+    capacity: Duration,
+
+    /// The time saved up as of `updated_at`.
+    saved: Duration,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter with a full bucket.
+    pub fn new(limit: &RateLimit, now: Instant) -> Self {
+        let (interval, capacity) = Self::dimensions(limit);
+        Self {
+            interval,
+            capacity,
+            saved: capacity,
+            updated_at: now,
+        }
+    }
+
+    /// Switch to a different limit. The saved time is kept, as long as it fits the new bucket.
+    pub fn set_limit(&mut self, limit: &RateLimit) {
+        let (interval, capacity) = Self::dimensions(limit);
+        self.interval = interval;
+        self.capacity = capacity;
+        self.saved = self.saved.min(capacity);
+    }
+
+    /// Returns the time it takes to earn one check, and the time it takes to earn a full bucket.
+    fn dimensions(limit: &RateLimit) -> (Duration, Duration) {
+        let interval = Duration::from_secs(60)
+            .checked_div(limit.checks_per_minute)
+            .unwrap_or(Duration::ZERO);
+        (interval, interval.saturating_mul(limit.burst))
+    }
+
+    /// Take a check out of the bucket.
+    ///
+    /// Returns how long to wait before starting the check, which is zero if the bucket wasn't
+    /// empty. The check is accounted for either way, so the caller is expected to wait.
+    pub fn acquire(&mut self, now: Instant) -> Duration {
+        let earned = now.saturating_duration_since(self.updated_at);
+        self.saved = self.saved.saturating_add(earned).min(self.capacity);
+        self.updated_at = now;
+
+        if self.saved >= self.interval {
+            self.saved = self.saved.saturating_sub(self.interval);
+            Duration::ZERO
+        } else {
+            let wait = self.interval.saturating_sub(self.saved);
+            self.saved = Duration::ZERO;
+            self.updated_at = now.checked_add(wait).unwrap_or(now);
+            wait
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn after(start: Instant, millis: u64) -> Instant {
+        start.checked_add(Duration::from_millis(millis)).unwrap()
+    }
+
+    #[test]
+    fn allows_a_burst_then_paces_checks() {
+        let start = Instant::now();
+        let limit = RateLimit {
+            checks_per_minute: 120,
+            burst: 3,
+        };
+        let mut limiter = RateLimiter::new(&limit, start);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.acquire(start), Duration::ZERO);
+        }
+        assert_eq!(limiter.acquire(start), Duration::from_millis(500));
+        // The previous check will start at 500 ms, so the next one has to wait until 1000 ms.
+        assert_eq!(
+            limiter.acquire(after(start, 500)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(limiter.acquire(after(start, 1500)), Duration::ZERO);
+    }
+
+    #[test]
+    fn bucket_does_not_overflow() {
+        let start = Instant::now();
+        let limit = RateLimit {
+            checks_per_minute: 60,
+            burst: 2,
+        };
+        let mut limiter = RateLimiter::new(&limit, start);
+
+        let later = after(start, 3_600_000);
+        assert_eq!(limiter.acquire(later), Duration::ZERO);
+        assert_eq!(limiter.acquire(later), Duration::ZERO);
+        assert_eq!(limiter.acquire(later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn new_limit_applies_to_the_saved_time() {
+        let start = Instant::now();
+        let limit = RateLimit {
+            checks_per_minute: 60,
+            burst: 10,
+        };
+        let mut limiter = RateLimiter::new(&limit, start);
+
+        limiter.set_limit(&RateLimit {
+            checks_per_minute: 30,
+            burst: 2,
+        });
+        assert_eq!(limiter.acquire(start), Duration::ZERO);
+        assert_eq!(limiter.acquire(start), Duration::ZERO);
+        assert_eq!(limiter.acquire(start), Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,240 @@
+//! Decide which instance to check next.
+use crate::{
+    db::{self, StatePolicy},
+    domain::Domain,
+    orchestrator::db_writer::{self, DbWriter},
+    with_loc,
+};
+use anyhow::Context;
+use rusqlite::Connection;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::{Duration, SystemTime};
+
+/// How many instances are kept in memory at most.
+const BATCH_SIZE: usize = 1024;
+/// How far into the future the scheduler looks for checks to pick up.
+const LOOKAHEAD: Duration = Duration::from_secs(3);
+/// How often the queue is topped up even if it isn't empty, so that instances that became due in
+/// the meantime don't have to wait for the whole batch to be checked.
+const REFILL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// A queue of instances that are due to be checked, sorted by the time of the check.
+///
+/// The queue is refilled from the database in batches. Instances are rescheduled in the database
+/// as soon as they're put into the queue, so each refill only picks up instances that aren't in the
+/// queue yet. Their checks are recorded as in flight at the same time, so if the crawler stops
+/// before getting to them, they're re-queued at the next start.
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<(SystemTime, Domain)>>,
+    batch_size: usize,
+    last_refill: Option<SystemTime>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::with_batch_size(BATCH_SIZE)
+    }
+
+    fn with_batch_size(batch_size: usize) -> Self {
+        Self {
+            queue: BinaryHeap::new(),
+            batch_size,
+            last_refill: None,
+        }
+    }
+
+    /// Take the instance with the earliest check time, if it's due within a few seconds of `now`.
+    ///
+    /// Returns the instance and the time at which it should be checked. The instance is already
+    /// rescheduled in the database, and its check is recorded as in flight; the caller should
+    /// finish it with `db_writer::Command::FinishCheck`.
+    ///
+    /// `conn` is used to read from the database; writes go through `writer`. The picked instances
+    /// are rescheduled according to `policy`.
+    pub fn next(
+        &mut self,
+        conn: &Connection,
+        writer: &DbWriter,
+        policy: &StatePolicy,
+        now: SystemTime,
+    ) -> anyhow::Result<Option<(Domain, SystemTime)>> {
+        let refilled_recently = self.last_refill.is_some_and(|last_refill| {
+            now.duration_since(last_refill)
+                .is_ok_and(|elapsed| elapsed < REFILL_INTERVAL)
+        });
+        if self.queue.is_empty() || !refilled_recently {
+            self.refill(conn, writer, policy, now)
+                .context(with_loc!("Refilling the scheduler's queue"))?;
+        }
+
+        Ok(self
+            .queue
+            .pop()
+            .map(|Reverse((check_time, instance))| (instance, check_time)))
+    }
+
+    fn refill(
+        &mut self,
+        conn: &Connection,
+        writer: &DbWriter,
+        policy: &StatePolicy,
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        let limit = self.batch_size.saturating_sub(self.queue.len());
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let until = now.checked_add(LOOKAHEAD).unwrap_or(now);
+        let batch = db::pick_due_instances(conn, until, limit)
+            .context(with_loc!("Picking due instances"))?;
+        let instances: Vec<Domain> = batch.iter().map(|(instance, _)| instance.clone()).collect();
+        writer
+            .execute(db_writer::Command::StartChecks {
+                instances,
+                policy: *policy,
+            })
+            .context(with_loc!("Rescheduling picked instances"))?;
+
+        self.queue.extend(
+            batch
+                .into_iter()
+                .map(|(instance, check_time)| Reverse((check_time, instance))),
+        );
+        self.last_refill = Some(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::time::Env;
+    use std::time::UNIX_EPOCH;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH
+            .checked_add(Duration::from_secs(seconds))
+            .unwrap()
+    }
+
+    struct Database {
+        conn: Connection,
+        writer: DbWriter,
+        _file: tempfile::NamedTempFile,
+    }
+
+    fn database_with(instances: &[(&str, u64)]) -> Database {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut conn = Connection::open(file.path()).unwrap();
+        db::init(&mut conn).unwrap();
+        let mastodon_social = Domain::from_str("mastodon.social").unwrap();
+        db::postpone(&mut conn, &mastodon_social, at(2_000_000_000)).unwrap();
+        for (hostname, check_time) in instances {
+            let instance = Domain::from_str(hostname).unwrap();
+            db::add_instance(&conn, &instance, Env::system()).unwrap();
+            db::postpone(&mut conn, &instance, at(*check_time)).unwrap();
+        }
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let (writer, _) = DbWriter::spawn(logger, Connection::open(file.path()).unwrap()).unwrap();
+        Database {
+            conn,
+            writer,
+            _file: file,
+        }
+    }
+
+    fn drain(scheduler: &mut Scheduler, database: &Database, now: SystemTime) -> Vec<String> {
+        let mut result = vec![];
+        while let Some((instance, _)) = scheduler
+            .next(
+                &database.conn,
+                &database.writer,
+                &StatePolicy::default(),
+                now,
+            )
+            .unwrap()
+        {
+            result.push(instance.to_string());
+        }
+        result
+    }
+
+    #[test]
+    fn picks_instances_in_order_of_check_time() {
+        let database = database_with(&[
+            ("b.example.com", 1_700_000_002),
+            ("a.example.com", 1_700_000_001),
+            ("c.example.com", 1_700_000_000),
+            ("later.example.com", 1_700_000_060),
+        ]);
+
+        let mut scheduler = Scheduler::new();
+        assert_eq!(
+            drain(&mut scheduler, &database, at(NOW)),
+            vec!["c.example.com", "a.example.com", "b.example.com"]
+        );
+
+        // The picked instances have been rescheduled, so they don't come up again.
+        assert_eq!(
+            drain(&mut scheduler, &database, at(1_700_000_060)),
+            vec!["later.example.com"]
+        );
+    }
+
+    #[test]
+    fn order_holds_across_batches() {
+        let database = database_with(&[
+            ("d.example.com", 1_700_000_000),
+            ("c.example.com", 1_700_000_001),
+            ("b.example.com", 1_700_000_002),
+            ("a.example.com", 1_700_000_003),
+        ]);
+
+        let mut scheduler = Scheduler::with_batch_size(3);
+        assert_eq!(
+            drain(&mut scheduler, &database, at(NOW)),
+            vec![
+                "d.example.com",
+                "c.example.com",
+                "b.example.com",
+                "a.example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn refill_picks_up_instances_that_became_due() {
+        let mut database = database_with(&[
+            ("a.example.com", 1_700_000_000),
+            ("b.example.com", 1_700_000_001),
+        ]);
+
+        let mut scheduler = Scheduler::new();
+        let (first, _) = scheduler
+            .next(
+                &database.conn,
+                &database.writer,
+                &StatePolicy::default(),
+                at(NOW),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.to_string(), "a.example.com");
+
+        // An instance that's due earlier than the ones in the queue gets ahead of them.
+        let urgent = Domain::from_str("urgent.example.com").unwrap();
+        db::add_instance(&database.conn, &urgent, Env::system()).unwrap();
+        db::postpone(&mut database.conn, &urgent, at(NOW)).unwrap();
+
+        assert_eq!(
+            drain(&mut scheduler, &database, at(1_700_000_003)),
+            vec!["urgent.example.com", "b.example.com"]
+        );
+    }
+}
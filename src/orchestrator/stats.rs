@@ -0,0 +1,278 @@
+//! Counters describing what the orchestrator has been up to since it started.
+use crate::{
+    db::CheckOutcome,
+    orchestrator::{instance_checker::CheckerExit, metrics::Metrics},
+};
+use slog::{info, Logger};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Stats {
+    /// Checkers that exited normally after checking the instance.
+    checker_successes: AtomicU64,
+
+    /// Checkers that exited with an error, i.e. the instance didn't respond properly.
+    checker_failures: AtomicU64,
+
+    /// Checkers that were terminated for exceeding their CPU time limit.
+    checker_cpu_limit_exceeded: AtomicU64,
+
+    /// Checkers that aborted, which most likely means they hit the address space limit.
+    checker_aborts: AtomicU64,
+
+    /// Checkers that were killed with SIGKILL, most likely by the OOM killer.
+    checker_kills: AtomicU64,
+
+    /// Checkers that we killed because they broke the protocol or stopped responding.
+    checker_stops: AtomicU64,
+
+    /// Checkers that were terminated by any other signal, e.g. SIGSEGV.
+    checker_crashes: AtomicU64,
+
+    /// Checks that found the instance alive.
+    alive_checks: AtomicU64,
+
+    /// Checks that found the instance not responding properly.
+    failed_checks: AtomicU64,
+
+    /// Checks that found the instance redirecting to another host.
+    moved_checks: AtomicU64,
+
+    /// Checks that couldn't tell whether the instance is alive.
+    inconclusive_checks: AtomicU64,
+
+    /// Checks where the checker closed its stdout without sending `Done` or `Failed`.
+    incomplete_checks: AtomicU64,
+
+    /// Checks that were postponed because other hosts at the same domain or address were being
+    /// checked.
+    postponed_checks: AtomicU64,
+
+    /// Checks that had to wait because of the global rate limit.
+    rate_limited_checks: AtomicU64,
+
+    /// The total time that checks spent waiting because of the global rate limit, in milliseconds.
+    rate_limit_delay_ms: AtomicU64,
+
+    /// Checks that were started.
+    started_checks: AtomicU64,
+
+    /// Checks that are running right now.
+    running_checks: AtomicUsize,
+
+    /// Peers that were handed over to the database, including the ones that were already there.
+    ingested_peers: AtomicU64,
+
+    /// How long the latest list generation took, in milliseconds.
+    list_generation_ms: AtomicU64,
+}
+
+impl Stats {
+    pub fn record_checker_exit(&self, exit: &CheckerExit) {
+        let counter = match exit {
+            CheckerExit::Success => &self.checker_successes,
+            CheckerExit::Failure(_) => &self.checker_failures,
+            CheckerExit::CpuLimitExceeded => &self.checker_cpu_limit_exceeded,
+            CheckerExit::Aborted => &self.checker_aborts,
+            CheckerExit::Killed => &self.checker_kills,
+            CheckerExit::Stopped => &self.checker_stops,
+            CheckerExit::Crashed(_) => &self.checker_crashes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_check_outcome(&self, outcome: CheckOutcome) {
+        let counter = match outcome {
+            CheckOutcome::Alive => &self.alive_checks,
+            CheckOutcome::Failed => &self.failed_checks,
+            CheckOutcome::Moved => &self.moved_checks,
+            CheckOutcome::Inconclusive => &self.inconclusive_checks,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_incomplete_check(&self) {
+        self.incomplete_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_postponed_check(&self) {
+        self.postponed_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limit_delay(&self, delay: Duration) {
+        let delay_ms = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+        self.rate_limited_checks.fetch_add(1, Ordering::Relaxed);
+        self.rate_limit_delay_ms
+            .fetch_add(delay_ms, Ordering::Relaxed);
+    }
+
+    pub fn record_check_start(&self) {
+        self.started_checks.fetch_add(1, Ordering::Relaxed);
+        self.running_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_check_end(&self) {
+        self.running_checks.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn running_checks(&self) -> usize {
+        self.running_checks.load(Ordering::Relaxed)
+    }
+
+    pub fn record_ingested_peers(&self, count: usize) {
+        let count = u64::try_from(count).unwrap_or(u64::MAX);
+        self.ingested_peers.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_list_generation(&self, duration: Duration) {
+        let duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        self.list_generation_ms
+            .store(duration_ms, Ordering::Relaxed);
+    }
+
+    /// Add the current values of all counters to `metrics`.
+    pub fn write_metrics(&self, metrics: &mut Metrics) {
+        metrics.describe(
+            "checks_started_total",
+            "counter",
+            "Checks that were started.",
+        );
+        metrics.sample(
+            "checks_started_total",
+            &[],
+            self.started_checks.load(Ordering::Relaxed),
+        );
+
+        metrics.describe(
+            "checks_finished_total",
+            "counter",
+            "Checks that were finished, by their outcome.",
+        );
+        for (outcome, counter) in [
+            ("alive", &self.alive_checks),
+            ("failed", &self.failed_checks),
+            ("moved", &self.moved_checks),
+            ("inconclusive", &self.inconclusive_checks),
+        ] {
+            metrics.sample(
+                "checks_finished_total",
+                &[("outcome", outcome)],
+                counter.load(Ordering::Relaxed),
+            );
+        }
+
+        metrics.describe(
+            "checker_exits_total",
+            "counter",
+            "Checker processes that exited, by how they exited.",
+        );
+        for (exit, counter) in [
+            ("success", &self.checker_successes),
+            ("failure", &self.checker_failures),
+            ("cpu_limit_exceeded", &self.checker_cpu_limit_exceeded),
+            ("aborted", &self.checker_aborts),
+            ("killed", &self.checker_kills),
+            ("stopped", &self.checker_stops),
+            ("crashed", &self.checker_crashes),
+        ] {
+            metrics.sample(
+                "checker_exits_total",
+                &[("exit", exit)],
+                counter.load(Ordering::Relaxed),
+            );
+        }
+
+        metrics.describe(
+            "incomplete_checks_total",
+            "counter",
+            "Checks where the checker stopped talking before finishing the check.",
+        );
+        metrics.sample(
+            "incomplete_checks_total",
+            &[],
+            self.incomplete_checks.load(Ordering::Relaxed),
+        );
+
+        metrics.describe(
+            "postponed_checks_total",
+            "counter",
+            "Checks that were postponed because of the politeness limits.",
+        );
+        metrics.sample(
+            "postponed_checks_total",
+            &[],
+            self.postponed_checks.load(Ordering::Relaxed),
+        );
+
+        metrics.describe(
+            "rate_limited_checks_total",
+            "counter",
+            "Checks that had to wait because of the global rate limit.",
+        );
+        metrics.sample(
+            "rate_limited_checks_total",
+            &[],
+            self.rate_limited_checks.load(Ordering::Relaxed),
+        );
+
+        metrics.describe(
+            "rate_limit_delay_seconds_total",
+            "counter",
+            "Time that checks spent waiting because of the global rate limit.",
+        );
+        metrics.sample(
+            "rate_limit_delay_seconds_total",
+            &[],
+            Duration::from_millis(self.rate_limit_delay_ms.load(Ordering::Relaxed)).as_secs_f64(),
+        );
+
+        metrics.describe(
+            "running_checks",
+            "gauge",
+            "Checks that are running right now.",
+        );
+        metrics.sample("running_checks", &[], self.running_checks());
+
+        metrics.describe(
+            "ingested_peers_total",
+            "counter",
+            "Peers that were handed over to the database, including the known ones.",
+        );
+        metrics.sample(
+            "ingested_peers_total",
+            &[],
+            self.ingested_peers.load(Ordering::Relaxed),
+        );
+
+        metrics.describe(
+            "list_generation_duration_seconds",
+            "gauge",
+            "How long the latest list generation took.",
+        );
+        metrics.sample(
+            "list_generation_duration_seconds",
+            &[],
+            Duration::from_millis(self.list_generation_ms.load(Ordering::Relaxed)).as_secs_f64(),
+        );
+    }
+
+    /// Write the current values of all counters into the log.
+    pub fn log(&self, logger: &Logger) {
+        info!(
+            logger, "Checker exit statistics";
+            "successes" => self.checker_successes.load(Ordering::Relaxed),
+            "failures" => self.checker_failures.load(Ordering::Relaxed),
+            "cpu_limit_exceeded" => self.checker_cpu_limit_exceeded.load(Ordering::Relaxed),
+            "aborts" => self.checker_aborts.load(Ordering::Relaxed),
+            "kills" => self.checker_kills.load(Ordering::Relaxed),
+            "stops" => self.checker_stops.load(Ordering::Relaxed),
+            "crashes" => self.checker_crashes.load(Ordering::Relaxed),
+            "incomplete_checks" => self.incomplete_checks.load(Ordering::Relaxed),
+            "postponed_checks" => self.postponed_checks.load(Ordering::Relaxed),
+            "rate_limited_checks" => self.rate_limited_checks.load(Ordering::Relaxed),
+            "rate_limit_delay_ms" => self.rate_limit_delay_ms.load(Ordering::Relaxed),
+            "running_checks" => self.running_checks(),
+            "ingested_peers" => self.ingested_peers.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,176 @@
+//! Tell systemd how the orchestrator is doing, using the sd_notify protocol.
+//!
+//! systemd passes the address of its notification socket in `$NOTIFY_SOCKET`. If the variable
+//! isn't set, e.g. when the crawler is started by hand, all notifications are silently dropped.
+use crate::with_loc;
+use anyhow::Context;
+use slog::{error, Logger};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+/// How often the status is updated when there's no watchdog, or when the watchdog is lax.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Notifier {
+    logger: Logger,
+
+    /// The unbound socket through which notifications are sent, and systemd's address.
+    socket: Option<(UnixDatagram, SocketAddr)>,
+
+    /// How often systemd expects to hear from us, if it watches us at all.
+    watchdog_timeout: Option<Duration>,
+
+    /// When `ping` last sent something.
+    last_ping: Option<Instant>,
+}
+
+impl Notifier {
+    /// Create a notifier from the variables that systemd put into the environment.
+    pub fn from_env(logger: Logger) -> anyhow::Result<Self> {
+        let Some(address) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(Self::disabled(logger));
+        };
+        let address = address
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("$NOTIFY_SOCKET is not valid UTF-8"))?;
+
+        let watchdog_is_ours =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog_timeout = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| watchdog_is_ours)
+            .map(|usec| usec.parse().map(Duration::from_micros))
+            .transpose()
+            .context(with_loc!("Parsing $WATCHDOG_USEC"))?;
+
+        Self::new(logger, &address, watchdog_timeout)
+    }
+
+    /// Create a notifier that sends to `address`. Addresses that start with "@" are in the
+    /// abstract namespace.
+    fn new(
+        logger: Logger,
+        address: &str,
+        watchdog_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        let address = match address.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(address),
+        }
+        .with_context(|| format!("Parsing the notification socket address {}", address))?;
+        let socket = UnixDatagram::unbound().context(with_loc!("Creating a datagram socket"))?;
+        Ok(Self {
+            logger,
+            socket: Some((socket, address)),
+            watchdog_timeout,
+            last_ping: None,
+        })
+    }
+
+    fn disabled(logger: Logger) -> Self {
+        Self {
+            logger,
+            socket: None,
+            watchdog_timeout: None,
+            last_ping: None,
+        }
+    }
+
+    /// Tell systemd that the orchestrator finished starting up.
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    /// Tell systemd that the orchestrator is shutting down.
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1\nSTATUS=Shutting down");
+    }
+
+    /// Tell systemd that the main loop is still going, along with what it's doing.
+    ///
+    /// This is meant to be called on every iteration of the main loop. Messages are only sent
+    /// as often as the watchdog requires, and `status` is only called when a message is sent.
+    pub fn ping<F: FnOnce() -> String>(&mut self, now: Instant, status: F) {
+        if self.socket.is_none() {
+            return;
+        }
+        // systemd recommends pinging at twice the rate the watchdog expects.
+        let interval = self
+            .watchdog_timeout
+            .map_or(STATUS_INTERVAL, |timeout| {
+                timeout.checked_div(2).unwrap_or(timeout)
+            })
+            .min(STATUS_INTERVAL);
+        if self
+            .last_ping
+            .is_some_and(|last| now.saturating_duration_since(last) < interval)
+        {
+            return;
+        }
+        self.last_ping = Some(now);
+
+        let mut message = format!("STATUS={}", status());
+        if self.watchdog_timeout.is_some() {
+            message.push_str("\nWATCHDOG=1");
+        }
+        self.notify(&message);
+    }
+
+    fn notify(&self, message: &str) {
+        let Some((socket, address)) = &self.socket else {
+            return;
+        };
+        if let Err(e) = socket.send_to_addr(message.as_bytes(), address) {
+            error!(self.logger, "Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn receive(socket: &UnixDatagram) -> Option<String> {
+        let mut buffer = [0; 1024];
+        let len = socket.recv(&mut buffer).ok()?;
+        Some(String::from_utf8(buffer.get(..len)?.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn pings_the_watchdog_twice_per_timeout() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut notifier =
+            Notifier::new(logger, path.to_str().unwrap(), Some(Duration::from_secs(4))).unwrap();
+
+        notifier.ready("Started");
+        assert_eq!(
+            receive(&systemd).as_deref(),
+            Some("READY=1\nSTATUS=Started")
+        );
+
+        let start = Instant::now();
+        notifier.ping(start, || "Running 1 check".to_string());
+        assert_eq!(
+            receive(&systemd).as_deref(),
+            Some("STATUS=Running 1 check\nWATCHDOG=1")
+        );
+
+        let soon = start.checked_add(Duration::from_secs(1)).unwrap();
+        notifier.ping(soon, || unreachable!("Status isn't needed yet"));
+        assert_eq!(receive(&systemd), None);
+
+        let later = start.checked_add(Duration::from_secs(2)).unwrap();
+        notifier.ping(later, || "Running 2 checks".to_string());
+        assert_eq!(
+            receive(&systemd).as_deref(),
+            Some("STATUS=Running 2 checks\nWATCHDOG=1")
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Run the instance state machine over simulated months, and check that instances move between
+//! states as shown in _instance_states.svg_.
+//!
+//! The database is in memory, and the clock only moves when the simulation says so, so a year of
+//! checks takes a fraction of a second. Checks happen whenever the database schedules them, and
+//! their outcomes are scripted by the tests.
+use crate::{
+    db::{self, CheckOutcome, CheckRecord, FailurePolicy, InstanceState, StatePolicy, Uptime},
+    domain::Domain,
+    ipc::FailureKind,
+    time::{Clock, Env, Rng},
+};
+use rusqlite::{params, Connection};
+use std::cell::{Cell, RefCell};
+use std::ops::RangeInclusive;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long checks are kept in the history; the same as the crawler's default.
+const RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+struct SimulatedClock(Cell<SystemTime>);
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> SystemTime {
+        self.0.get()
+    }
+}
+
+struct SeededRng(RefCell<fastrand::Rng>);
+
+impl Rng for SeededRng {
+    fn i64(&self, range: RangeInclusive<i64>) -> i64 {
+        self.0.borrow_mut().i64(range)
+    }
+}
+
+/// What happens when the instance is checked.
+#[derive(Clone, Copy)]
+enum Outcome {
+    Alive,
+    Fails(FailureKind),
+    RedirectsTo(&'static str),
+}
+
+struct Simulation {
+    conn: Connection,
+    clock: SimulatedClock,
+    rng: SeededRng,
+    instance: Domain,
+    failure_policy: FailurePolicy,
+    state_policy: StatePolicy,
+}
+
+impl Simulation {
+    /// A database with a single, freshly discovered instance in it.
+    fn new() -> Self {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let simulation = Self {
+            conn,
+            clock: SimulatedClock(Cell::new(
+                UNIX_EPOCH
+                    .checked_add(Duration::from_secs(1_700_000_000))
+                    .unwrap(),
+            )),
+            rng: SeededRng(RefCell::new(fastrand::Rng::with_seed(42))),
+            instance: Domain::from_str("example.com").unwrap(),
+            failure_policy: FailurePolicy::default(),
+            state_policy: StatePolicy::default(),
+        };
+        db::add_instance(&simulation.conn, &simulation.instance, simulation.env()).unwrap();
+        simulation
+    }
+
+    fn env(&self) -> Env<'_> {
+        Env {
+            clock: &self.clock,
+            rng: &self.rng,
+        }
+    }
+
+    fn state(&self) -> InstanceState {
+        self.conn
+            .query_row(
+                "SELECT state FROM instances WHERE hostname = ?1",
+                params![self.instance.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    fn uptime(&self) -> Uptime {
+        db::get_uptimes(&self.conn, self.env())
+            .unwrap()
+            .into_iter()
+            .find(|(hostname, _, _)| *hostname == self.instance.to_string())
+            .map(|(_, _, uptime)| uptime)
+            .unwrap()
+    }
+
+    fn next_check(&self) -> SystemTime {
+        let next_check: u64 = self
+            .conn
+            .query_row(
+                "SELECT next_check_datetime FROM instances WHERE hostname = ?1",
+                params![self.instance.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        UNIX_EPOCH
+            .checked_add(Duration::from_secs(next_check))
+            .unwrap()
+    }
+
+    /// Let `days` pass, checking the instance whenever it's due. Every check has the given
+    /// `outcome`. Returns the number of checks.
+    fn run(&mut self, days: u32, outcome: Outcome) -> u32 {
+        let end = self
+            .clock
+            .now()
+            .checked_add(DAY.checked_mul(days).unwrap())
+            .unwrap();
+        let mut checks = 0_u32;
+        loop {
+            let next_check = self.next_check().max(self.clock.now());
+            if next_check > end {
+                self.clock.0.set(end);
+                return checks;
+            }
+            self.clock.0.set(next_check);
+            self.check(outcome);
+            checks = checks.checked_add(1).unwrap();
+        }
+    }
+
+    /// Like `run`, but the instance also shows up in a peers list every day, as dead hosts do in
+    /// Mastodon's lists.
+    fn run_listed(&mut self, days: u32, outcome: Outcome) -> u32 {
+        let mut checks = 0_u32;
+        for _ in 0..days {
+            checks = checks.checked_add(self.run(1, outcome)).unwrap();
+            self.list_in_peers();
+        }
+        checks
+    }
+
+    fn list_in_peers(&self) {
+        db::note_seen_in_peers(&self.conn, &self.instance, &self.state_policy, self.env()).unwrap();
+    }
+
+    fn check(&mut self, outcome: Outcome) {
+        let env = Env {
+            clock: &self.clock,
+            rng: &self.rng,
+        };
+        let instance = std::slice::from_ref(&self.instance);
+        db::start_checks(&mut self.conn, instance, &self.state_policy, env).unwrap();
+        let check_outcome = match outcome {
+            Outcome::Alive => {
+                db::mark_alive(
+                    &mut self.conn,
+                    &self.instance,
+                    false,
+                    &self.state_policy,
+                    env,
+                )
+                .unwrap();
+                CheckOutcome::Alive
+            }
+            Outcome::Fails(kind) => {
+                db::mark_dead(
+                    &mut self.conn,
+                    &self.instance,
+                    kind,
+                    &self.failure_policy,
+                    &self.state_policy,
+                    env,
+                )
+                .unwrap();
+                CheckOutcome::Failed
+            }
+            Outcome::RedirectsTo(target) => {
+                let target = Domain::from_str(target).unwrap();
+                db::mark_moved(
+                    &mut self.conn,
+                    &self.instance,
+                    &target,
+                    &self.state_policy,
+                    env,
+                )
+                .unwrap();
+                CheckOutcome::Moved
+            }
+        };
+        db::finish_check(&self.conn, &self.instance).unwrap();
+        let record = CheckRecord {
+            outcome: check_outcome,
+            duration: Duration::from_secs(1),
+            peers_count: None,
+        };
+        db::record_check(&mut self.conn, &self.instance, &record, RETENTION, env).unwrap();
+    }
+}
+
+#[test]
+fn discovered_instance_can_go_anywhere() {
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::Alive);
+    assert_eq!(simulation.state(), InstanceState::Alive);
+
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dying);
+
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::RedirectsTo("new.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moving);
+}
+
+#[test]
+fn failing_instance_dies_after_a_week_and_can_come_back() {
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::Alive);
+
+    simulation.run(6, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dying);
+    simulation.run(3, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dead);
+
+    simulation.run(10, Outcome::Alive);
+    assert_eq!(simulation.state(), InstanceState::Alive);
+}
+
+#[test]
+fn redirected_instance_moves_after_a_week_and_can_move_again() {
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::Alive);
+
+    simulation.run(6, Outcome::RedirectsTo("new.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moving);
+    simulation.run(3, Outcome::RedirectsTo("new.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moved);
+
+    // A new target starts the week over. The first check in "moving" state is still scheduled
+    // a week out, like all the checks of "moved" instances.
+    simulation.run(10, Outcome::RedirectsTo("newer.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moving);
+    simulation.run(16, Outcome::RedirectsTo("newer.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moved);
+
+    simulation.run(10, Outcome::Alive);
+    assert_eq!(simulation.state(), InstanceState::Alive);
+}
+
+#[test]
+fn transient_states_interrupt_each_other() {
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::Alive);
+
+    simulation.run(3, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dying);
+    simulation.run(3, Outcome::RedirectsTo("new.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moving);
+    simulation.run(3, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dying);
+
+    // The week starts over after every interruption.
+    simulation.run(4, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dying);
+}
+
+#[test]
+fn stable_states_can_become_transient_again() {
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::Alive);
+    simulation.run(10, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dead);
+    simulation.run(10, Outcome::RedirectsTo("new.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moving);
+
+    simulation.run(16, Outcome::RedirectsTo("new.example.com"));
+    assert_eq!(simulation.state(), InstanceState::Moved);
+    simulation.run(10, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dying);
+}
+
+#[test]
+fn dead_instance_is_checked_less_and_less_often() {
+    let mut simulation = Simulation::new();
+    simulation.run(2, Outcome::Alive);
+    simulation.run(10, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dead);
+
+    // Weekly checks would be 52 a year; backoff brings that down to about a week, two weeks,
+    // a month, two months, and then every 90 days.
+    let checks = simulation.run(365, Outcome::Fails(FailureKind::Timeout));
+    assert!((5..=9).contains(&checks), "{} checks", checks);
+    assert_eq!(simulation.state(), InstanceState::Dead);
+}
+
+#[test]
+fn dead_instance_backs_off_even_if_peers_keep_listing_it() {
+    let mut simulation = Simulation::new();
+    simulation.run_listed(2, Outcome::Alive);
+    simulation.run_listed(10, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dead);
+
+    let checks = simulation.run_listed(365, Outcome::Fails(FailureKind::Timeout));
+    assert!((5..=9).contains(&checks), "{} checks", checks);
+    assert_eq!(simulation.state(), InstanceState::Dead);
+}
+
+#[test]
+fn dead_instance_is_checked_soon_after_it_reappears_in_peers_lists() {
+    let mut simulation = Simulation::new();
+    simulation.run_listed(2, Outcome::Alive);
+    simulation.run_listed(10, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.state(), InstanceState::Dead);
+
+    // Dropped out of the lists and backed off to checks about two months apart.
+    simulation.run(120, Outcome::Fails(FailureKind::Timeout));
+    let in_eight_days = simulation
+        .clock
+        .now()
+        .checked_add(DAY.checked_mul(8).unwrap())
+        .unwrap();
+    assert!(simulation.next_check() > in_eight_days);
+
+    simulation.list_in_peers();
+    assert!(simulation.next_check() <= in_eight_days);
+}
+
+#[test]
+fn uptime_covers_the_last_7_30_and_90_days() {
+    let mut simulation = Simulation::new();
+    assert_eq!(
+        simulation.uptime(),
+        Uptime {
+            last_7_days: None,
+            last_30_days: None,
+            last_90_days: None,
+        }
+    );
+
+    simulation.run(60, Outcome::Alive);
+    simulation.run(20, Outcome::Fails(FailureKind::Timeout));
+    // Uptime counts checks, not days: once the instance is dead, it's checked weekly rather than
+    // daily, so the last 20 days weigh about as much as the 10 alive days before them.
+    let uptime = simulation.uptime();
+    assert_eq!(uptime.last_7_days, Some(0.0));
+    let last_30_days = uptime.last_30_days.unwrap();
+    assert!((40.0..60.0).contains(&last_30_days), "{}", last_30_days);
+    let last_90_days = uptime.last_90_days.unwrap();
+    assert!((80.0..92.0).contains(&last_90_days), "{}", last_90_days);
+
+    // Checks older than the retention period are forgotten.
+    simulation.run(95, Outcome::Fails(FailureKind::Timeout));
+    assert_eq!(simulation.uptime().last_90_days, Some(0.0));
+    let (oldest, newest): (u64, u64) = simulation
+        .conn
+        .query_row(
+            "SELECT min(checked_at), max(checked_at) FROM check_history",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert!(newest.checked_sub(oldest).unwrap() <= RETENTION.as_secs());
+}
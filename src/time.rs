@@ -32,8 +32,8 @@
 //! check will accumulate about 5.76 * 2 ≈ 11.5 hours of "spread" — which is exactly the number of
 //! "spread" we give to a "weekly" check.
 //!
-//! The functions that implement those techniques are [`about_a_day_from_now()`] and
-//! [`about_a_week_from_now()`].
+//! These are the defaults, [`CheckPeriod::DAILY`] and [`CheckPeriod::WEEKLY`]; the periods for
+//! each instance state can be changed in the configuration file, see `db::StatePolicy`.
 //!
 //! This module also has a [`in_about_six_hours()`] function, which is used when generating
 //! a list of "alive" instances. That task is periodic, and uses a slightly odd period of 6 hours
@@ -44,17 +44,78 @@
 //! still employ randomness though, so when a bunch  of instances are added simultaneously, they
 //! won't all get scheduled onto the same time. The amount of randomness is bigger than with the
 //! other two functions; it's any number of seconds from 0 to 29 hours (both inclusive).
+//!
+//! When a check has to be postponed because we're already busy with the same host, it's moved
+//! [`in_about_a_minute()`]: 60 seconds plus or minus 30.
+//!
+//! All of these functions get the current time and the randomness from an [`Env`]. The crawler
+//! uses [`Env::system()`]; tests can substitute their own clock and random numbers, so they can
+//! go through weeks of checks in an instant.
 use anyhow::anyhow;
-use std::ops::{RangeBounds, RangeInclusive};
+use serde::Deserialize;
+use std::ops::RangeInclusive;
 use std::time::{Duration, SystemTime};
 
 const DAY_HOURS_IN_SECONDS: u64 = 29 * 3600;
 
+/// A source of the current time.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// A source of random numbers.
+pub trait Rng {
+    /// A random number from `range`.
+    fn i64(&self, range: RangeInclusive<i64>) -> i64;
+}
+
+/// The real time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// `fastrand`'s thread-local generator.
+pub struct SystemRng;
+
+impl Rng for SystemRng {
+    fn i64(&self, range: RangeInclusive<i64>) -> i64 {
+        fastrand::i64(range)
+    }
+}
+
+/// The clock and the random numbers that scheduling is based on.
+#[derive(Clone, Copy)]
+pub struct Env<'a> {
+    pub clock: &'a dyn Clock,
+    pub rng: &'a dyn Rng,
+}
+
+impl Env<'static> {
+    /// The real time and real randomness.
+    pub fn system() -> Self {
+        Self {
+            clock: &SystemClock,
+            rng: &SystemRng,
+        }
+    }
+}
+
+impl Env<'_> {
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+}
+
 fn now_plus_offset_plus_random_from_range(
+    env: Env,
     fixed_offset: Duration,
-    range: impl RangeBounds<i64>,
+    range: RangeInclusive<i64>,
 ) -> anyhow::Result<SystemTime> {
-    let random_offset = fastrand::i64(range);
+    let random_offset = env.rng.i64(range);
 
     // Convert fixed_offset to seconds and add the random offset
     let final_offset_seconds = if random_offset >= 0 {
@@ -65,13 +126,13 @@ fn now_plus_offset_plus_random_from_range(
     } else {
         fixed_offset
             .as_secs()
-            .checked_sub(random_offset.unsigned_abs() as u64)
+            .checked_sub(random_offset.unsigned_abs())
             .ok_or_else(|| {
                 anyhow!("Failed to subtract random offset from fixed offset due to underflow")
             })?
     };
 
-    let now = SystemTime::now();
+    let now = env.now();
 
     let final_time = now
         .checked_add(Duration::from_secs(final_offset_seconds))
@@ -85,37 +146,75 @@ fn now_plus_offset_plus_random_from_range(
     Ok(final_time)
 }
 
-/// Random datetime about a day from now (now + 29 hours ± 2 hours).
-pub fn about_a_day_from_now() -> anyhow::Result<SystemTime> {
-    const TWO_HOURS_SECS: i64 = 2 * 60 * 60;
-    const RAND_RANGE: RangeInclusive<i64> = -TWO_HOURS_SECS..=TWO_HOURS_SECS;
-    let starting_point = Duration::from_secs(DAY_HOURS_IN_SECONDS);
-    now_plus_offset_plus_random_from_range(starting_point, RAND_RANGE)
+/// How often a periodic check runs: every `period_seconds`, give or take `jitter_seconds`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CheckPeriod {
+    pub period_seconds: u64,
+    pub jitter_seconds: u64,
 }
 
-/// Random datetime about a week away from now (now + 167 hours ± 11.5 hours).
-pub fn about_a_week_from_now() -> anyhow::Result<SystemTime> {
-    const ELEVEN_AND_A_HALF_HOURS_SECS: i64 = (11 * 60 + 30) * 60;
-    const RAND_RANGE: RangeInclusive<i64> =
-        -ELEVEN_AND_A_HALF_HOURS_SECS..=ELEVEN_AND_A_HALF_HOURS_SECS;
-    let hours = 167;
-    let starting_point = Duration::from_secs(hours * 3600);
-    now_plus_offset_plus_random_from_range(starting_point, RAND_RANGE)
+impl CheckPeriod {
+    /// 29 hours ± 2 hours.
+    pub const DAILY: Self = Self {
+        period_seconds: DAY_HOURS_IN_SECONDS,
+        jitter_seconds: 2 * 60 * 60,
+    };
+
+    /// 167 hours ± 11.5 hours.
+    pub const WEEKLY: Self = Self {
+        period_seconds: 167 * 60 * 60,
+        jitter_seconds: (11 * 60 + 30) * 60,
+    };
+
+    /// Stretch the period to `at_least`, but no further than `cap`; periods that are already longer
+    /// stay as they are. The jitter is scaled along with the period, so the checks stay as spread
+    /// out as before.
+    pub fn stretched(&self, at_least: Duration, cap: Duration) -> Self {
+        let period_seconds = at_least.min(cap).as_secs().max(self.period_seconds);
+        let jitter_seconds = u128::from(self.jitter_seconds)
+            .checked_mul(u128::from(period_seconds))
+            .and_then(|jitter| jitter.checked_div(u128::from(self.period_seconds)))
+            .and_then(|jitter| u64::try_from(jitter).ok())
+            .unwrap_or(self.jitter_seconds);
+        Self {
+            period_seconds,
+            jitter_seconds,
+        }
+    }
+
+    /// Random datetime about a period from now.
+    pub fn next_check(&self, env: Env) -> anyhow::Result<SystemTime> {
+        let jitter = i64::try_from(self.jitter_seconds)
+            .map_err(|_| anyhow!("Jitter of {} seconds is too large", self.jitter_seconds))?;
+        now_plus_offset_plus_random_from_range(
+            env,
+            Duration::from_secs(self.period_seconds),
+            jitter.saturating_neg()..=jitter,
+        )
+    }
 }
 
 /// Random datetime no further than 29 hours from now.
-pub fn sometime_today() -> anyhow::Result<SystemTime> {
+pub fn sometime_today(env: Env) -> anyhow::Result<SystemTime> {
     now_plus_offset_plus_random_from_range(
+        env,
         Duration::from_secs(0),
         0..=(DAY_HOURS_IN_SECONDS as i64),
     )
 }
 
+/// Random datetime about a minute from now (now + 60 seconds ± 30 seconds).
+pub fn in_about_a_minute(env: Env) -> anyhow::Result<SystemTime> {
+    const RAND_RANGE: RangeInclusive<i64> = -30..=30;
+    now_plus_offset_plus_random_from_range(env, Duration::from_secs(60), RAND_RANGE)
+}
+
 /// Random datetime about 6.1 hours from now (now + 6 hours 6 minutes ± 5 minutes).
-pub fn in_about_six_hours() -> anyhow::Result<SystemTime> {
+pub fn in_about_six_hours(env: Env) -> anyhow::Result<SystemTime> {
     const FIVE_MINUTES_SECS: i64 = 5 * 60;
     const SIX_HOURS_SIX_MINUTES_SECS: u64 = (6 * 60 + 6) * 60;
     let six_hours_six_minutes_duration = Duration::from_secs(SIX_HOURS_SIX_MINUTES_SECS);
     const RAND_RANGE: RangeInclusive<i64> = -FIVE_MINUTES_SECS..=FIVE_MINUTES_SECS;
-    now_plus_offset_plus_random_from_range(six_hours_six_minutes_duration, RAND_RANGE)
+    now_plus_offset_plus_random_from_range(env, six_hours_six_minutes_duration, RAND_RANGE)
 }
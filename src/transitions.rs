@@ -0,0 +1,40 @@
+//! Print how instances changed states, to debug surprising changes after the fact.
+use crate::{
+    db::{self, TransitionFilter},
+    domain::Domain,
+};
+
+/// Which state transitions to print.
+pub enum Query {
+    /// All transitions of the given host.
+    Host(String),
+
+    /// Transitions of all hosts between two dates, in UTC.
+    Between(String, String),
+}
+
+pub fn main(query: Query) -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+
+    let filter = match query {
+        Query::Host(host) => TransitionFilter::Instance(Domain::from_str(&host)?),
+        Query::Between(since, until) => TransitionFilter::Between(
+            db::parse_datetime(&conn, &since)?,
+            db::parse_datetime(&conn, &until)?,
+        ),
+    };
+
+    for transition in db::get_state_transitions(&conn, &filter)? {
+        print!(
+            "{} {}: {} -> {} ({})",
+            transition.at, transition.hostname, transition.from, transition.to, transition.reason
+        );
+        match transition.redirect_target {
+            Some(target) => println!(", redirects to {}", target),
+            None => println!(),
+        }
+    }
+
+    Ok(())
+}